@@ -1,5 +1,8 @@
 use zordon::prelude::*;
 
+pub const DOS_SIG: u16 = 0x5A4D;
+pub const DOS_HDR_SIZE: usize = 0x40;
+
 #[derive(MutView)]
 pub struct DosHeader<'a> {
     pub mz_sig: MulByteView<'a, u16, LitEnd>,
@@ -0,0 +1,168 @@
+use crate::nt_hdr::DataDirType;
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeError {
+    Truncated { offset: usize, needed: usize },
+    BadDosSignature { found: u16 },
+    BadNtSignature { found: u32 },
+    BadNewExeHdrOffset { offset: u32 },
+    BadOptHdrMagic { found: u16 },
+    BadOptHdrSize { size: u16 },
+    SectionDataOutOfBounds { index: usize },
+    SectionDataOverlap { index: usize },
+    RvaNotMapped { rva: u32 },
+    SectionNotFound { va: u32 },
+    SectionNameTooLong { len: usize },
+    NoRoomForSectionHeader,
+    DataDirNotPresent { dir: DataDirType },
+    AddressOverflow,
+    DuplicateExport,
+    ExportNotFound,
+    ExportOrdinalOverflow,
+    UnsupportedRelocType { reloc_type: u8 },
+    RelocMissingHighAdjParam { rva: u32 },
+    BadRelocBlockSize { offset: usize, size: u32 },
+    BadSectionIndex { index: usize },
+    SectionWouldOverlap { index: usize },
+    SectionHasEntryPoint { index: usize },
+    SectionInUse { index: usize, dir: DataDirType },
+    VaNotMapped { va: u64 },
+    RvaHasNoRawData { rva: u32 },
+    OffsetNotMapped { offset: usize },
+    BadResourceEntry { offset: usize },
+    ResourceNotFound,
+    BadVersionInfo { offset: usize },
+    BadUnwindInfo { rva: u32 },
+    UnsupportedMachine { machine: u16 },
+    VersionBlockTooLarge { len: usize },
+    BadImageBase { base: u64 },
+}
+
+impl fmt::Display for PeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Truncated { offset, needed } => write!(
+                f,
+                "Buffer too small: {:#X} bytes needed at offset {:#X}",
+                needed, offset
+            ),
+            Self::BadDosSignature { found } => {
+                write!(f, "Bad DOS signature: {:#X}", found)
+            }
+            Self::BadNtSignature { found } => write!(f, "Bad NT signature: {:#X}", found),
+            Self::BadNewExeHdrOffset { offset } => {
+                write!(f, "Bad NT header offset: {:#X}", offset)
+            }
+            Self::BadOptHdrMagic { found } => {
+                write!(f, "Bad optional header magic: {:#X}", found)
+            }
+            Self::BadOptHdrSize { size } => {
+                write!(f, "Bad optional header size: {:#X}", size)
+            }
+            Self::SectionDataOutOfBounds { index } => write!(
+                f,
+                "Raw data of section {} starts past the end of the buffer",
+                index
+            ),
+            Self::SectionDataOverlap { index } => {
+                write!(f, "Raw data of section {} overlaps the headers", index)
+            }
+            Self::RvaNotMapped { rva } => {
+                write!(f, "Could not find section rva resides in: {:#X}", rva)
+            }
+            Self::SectionNotFound { va } => {
+                write!(f, "Could not find section with va: {:#X}", va)
+            }
+            Self::SectionNameTooLong { len } => {
+                write!(f, "Section name is {} bytes, the maximum is 8", len)
+            }
+            Self::NoRoomForSectionHeader => {
+                write!(f, "No room in the headers for another section header")
+            }
+            Self::DataDirNotPresent { dir } => {
+                write!(f, "{:?} data directory is not present", dir)
+            }
+            Self::AddressOverflow => write!(f, "Address or size overflows the 32 bit image"),
+            Self::DuplicateExport => write!(f, "An export with that name already exists"),
+            Self::ExportNotFound => write!(f, "Could not find export"),
+            Self::ExportOrdinalOverflow => write!(f, "Export ordinal does not fit in 16 bits"),
+            Self::UnsupportedRelocType { reloc_type } => {
+                write!(f, "Cannot apply relocation type: {}", reloc_type)
+            }
+            Self::RelocMissingHighAdjParam { rva } => {
+                write!(f, "HIGHADJ relocation at {:#X} has no following entry", rva)
+            }
+            Self::BadRelocBlockSize { offset, size } => write!(
+                f,
+                "Relocation block at {:#X} has a size smaller than its header: {:#X}",
+                offset, size
+            ),
+            Self::BadSectionIndex { index } => write!(f, "No section at index: {}", index),
+            Self::SectionWouldOverlap { index } => write!(
+                f,
+                "Section {} would overlap the next section's virtual range",
+                index
+            ),
+            Self::SectionHasEntryPoint { index } => {
+                write!(f, "Section {} contains the entrypoint", index)
+            }
+            Self::SectionInUse { index, dir } => write!(
+                f,
+                "Section {} is referenced by the {:?} data directory",
+                index, dir
+            ),
+            Self::VaNotMapped { va } => write!(f, "Va is not mapped by the image: {:#X}", va),
+            Self::RvaHasNoRawData { rva } => {
+                write!(f, "Rva is zero filled and has no file offset: {:#X}", rva)
+            }
+            Self::OffsetNotMapped { offset } => {
+                write!(f, "File offset is not mapped by the image: {:#X}", offset)
+            }
+            Self::BadResourceEntry { offset } => write!(
+                f,
+                "Resource directory at {:#X} has an entry at the wrong level",
+                offset
+            ),
+            Self::ResourceNotFound => write!(f, "Could not find resource"),
+            Self::BadVersionInfo { offset } => {
+                write!(f, "Malformed version info block at offset {:#X}", offset)
+            }
+            Self::BadUnwindInfo { rva } => write!(f, "Malformed unwind info at rva {:#X}", rva),
+            Self::UnsupportedMachine { machine } => {
+                write!(f, "Not supported for machine type: {:#X}", machine)
+            }
+            Self::VersionBlockTooLarge { len } => write!(
+                f,
+                "Version info block is {:#X} bytes, the maximum is 0xFFFF",
+                len
+            ),
+            Self::BadImageBase { base } => write!(f, "Bad image base: {:#X}", base),
+        }
+    }
+}
+
+#[cfg(test)]
+use alloc::format;
+
+#[test]
+fn pe_error_display() {
+    assert_eq!(
+        format!("{}", PeError::RvaNotMapped { rva: 0x3140 }),
+        "Could not find section rva resides in: 0x3140"
+    );
+    assert_eq!(
+        format!("{}", PeError::SectionNotFound { va: 0x1000 }),
+        "Could not find section with va: 0x1000"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            PeError::Truncated {
+                offset: 0x1C8,
+                needed: 0xC8
+            }
+        ),
+        "Buffer too small: 0xC8 bytes needed at offset 0x1C8"
+    );
+}
@@ -0,0 +1,530 @@
+use crate::{
+    error::PeError,
+    nt_hdr::{DataDirType, IMAGE_FILE_MACHINE_AMD64},
+    pe::PeHeader,
+    util::{align_up, IterWriteBack, ROCursor, RWCursor},
+};
+use alloc::prelude::v1::*;
+#[allow(unused_imports)]
+use assert_hex::assert_eq_hex;
+use byteorder::LittleEndian;
+use core::cell::Ref;
+use zordon::prelude::*;
+
+pub const RUNTIME_FUNCTION_SIZE: usize = 0xC;
+pub const UNWIND_INFO_HDR_SIZE: usize = 4;
+
+pub const UNW_FLAG_NHANDLER: u8 = 0;
+pub const UNW_FLAG_EHANDLER: u8 = 1;
+pub const UNW_FLAG_UHANDLER: u8 = 2;
+pub const UNW_FLAG_CHAININFO: u8 = 4;
+
+// An x64 .pdata entry, end_addr is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RuntimeFunction {
+    pub begin_addr: u32,
+    pub end_addr: u32,
+    pub unwind_info_addr: u32,
+}
+
+impl RuntimeFunction {
+    pub fn new(buf: &[u8]) -> Result<Self, PeError> {
+        let mut cur = ROCursor::new(buf);
+
+        Ok(Self {
+            begin_addr: cur.read_u32::<LittleEndian>()?,
+            end_addr: cur.read_u32::<LittleEndian>()?,
+            unwind_info_addr: cur.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    pub fn write(&self, buf: &mut RWCursor) -> Result<(), PeError> {
+        buf.write_u32::<LittleEndian>(self.begin_addr)?;
+        buf.write_u32::<LittleEndian>(self.end_addr)?;
+        buf.write_u32::<LittleEndian>(self.unwind_info_addr)
+    }
+
+    pub fn contains(&self, rva: u32) -> bool {
+        rva >= self.begin_addr && rva < self.end_addr
+    }
+}
+
+// Ends with the buffer, or with an error if it stops part way through an entry
+pub struct RuntimeFunctionIter<'a> {
+    cur: ROCursor<'a>,
+    done: bool,
+}
+
+impl<'a> RuntimeFunctionIter<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            cur: ROCursor::new(buf),
+            done: false,
+        }
+    }
+
+    fn read_next(&mut self) -> Result<RuntimeFunction, PeError> {
+        Ok(RuntimeFunction {
+            begin_addr: self.cur.read_u32::<LittleEndian>()?,
+            end_addr: self.cur.read_u32::<LittleEndian>()?,
+            unwind_info_addr: self.cur.read_u32::<LittleEndian>()?,
+        })
+    }
+}
+
+impl<'a> Iterator for RuntimeFunctionIter<'a> {
+    type Item = Result<RuntimeFunction, PeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.cur.remaining() == 0 {
+            return None;
+        }
+
+        let r = self.read_next();
+        self.done = r.is_err();
+        Some(r)
+    }
+}
+
+pub struct RuntimeFunctions;
+
+impl<'a> IterWriteBack<'a> for RuntimeFunctions {
+    type Iter = RuntimeFunctionIter<'a>;
+    type Output = RuntimeFunction;
+
+    fn iter(buf: &'a [u8]) -> Self::Iter {
+        RuntimeFunctionIter::new(buf)
+    }
+
+    fn write_single(buf: &mut RWCursor, func: &Self::Output) -> Result<(), PeError> {
+        func.write(buf)
+    }
+}
+
+// Registers are numbered RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8-R15, or XMM0-XMM15 for the
+// XMM saves. Offsets and sizes are in bytes, already unscaled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnwindOp {
+    PushNonVol { reg: u8 },
+    AllocLarge { size: u32 },
+    AllocSmall { size: u32 },
+    SetFpReg,
+    SaveNonVol { reg: u8, offset: u32 },
+    SaveNonVolFar { reg: u8, offset: u32 },
+    Epilog { info: u8, param: u16 }, // SAVE_XMM in version 1
+    Spare { info: u8, param: u32 },  // SAVE_XMM_FAR in version 1
+    SaveXmm128 { reg: u8, offset: u32 },
+    SaveXmm128Far { reg: u8, offset: u32 },
+    PushMachFrame { error_code: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnwindCode {
+    pub code_offset: u8, // Offset from the start of the prolog of the instruction after the op
+    pub op: UnwindOp,
+}
+
+// Each code takes one 16 bit slot plus however many its op needs for operands
+fn read_unwind_code(cur: &mut ROCursor, rva: u32) -> Result<UnwindCode, PeError> {
+    let code_offset = cur.read_u8()?;
+    let op_info = cur.read_u8()?;
+    let info = op_info >> 4;
+
+    let op = match op_info & 0xF {
+        0 => UnwindOp::PushNonVol { reg: info },
+        1 if info == 0 => UnwindOp::AllocLarge {
+            size: cur.read_u16::<LittleEndian>()? as u32 * 8,
+        },
+        1 => UnwindOp::AllocLarge {
+            size: cur.read_u32::<LittleEndian>()?,
+        },
+        2 => UnwindOp::AllocSmall {
+            size: info as u32 * 8 + 8,
+        },
+        3 => UnwindOp::SetFpReg,
+        4 => UnwindOp::SaveNonVol {
+            reg: info,
+            offset: cur.read_u16::<LittleEndian>()? as u32 * 8,
+        },
+        5 => UnwindOp::SaveNonVolFar {
+            reg: info,
+            offset: cur.read_u32::<LittleEndian>()?,
+        },
+        6 => UnwindOp::Epilog {
+            info,
+            param: cur.read_u16::<LittleEndian>()?,
+        },
+        7 => UnwindOp::Spare {
+            info,
+            param: cur.read_u32::<LittleEndian>()?,
+        },
+        8 => UnwindOp::SaveXmm128 {
+            reg: info,
+            offset: cur.read_u16::<LittleEndian>()? as u32 * 16,
+        },
+        9 => UnwindOp::SaveXmm128Far {
+            reg: info,
+            offset: cur.read_u32::<LittleEndian>()?,
+        },
+        10 => UnwindOp::PushMachFrame {
+            error_code: info != 0,
+        },
+        _ => return Err(PeError::BadUnwindInfo { rva }),
+    };
+
+    Ok(UnwindCode { code_offset, op })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnwindInfo {
+    pub version: u8,
+    pub flags: u8,
+    pub size_of_prolog: u8,
+    pub count_of_codes: u8, // In slots, not codes
+    pub frame_register: u8, // 0 when there is no frame pointer
+    pub frame_offset: u8,   // Scaled by 16
+    pub codes: Vec<UnwindCode>,
+    pub handler_rva: Option<u32>,
+    pub handler_data_rva: Option<u32>,
+    pub chained: Option<RuntimeFunction>,
+}
+
+impl UnwindInfo {
+    // buf starts at the unwind info, rva is where it lives so the handler data can be located
+    pub fn new(buf: &[u8], rva: u32) -> Result<Self, PeError> {
+        if buf.len() < UNWIND_INFO_HDR_SIZE {
+            return Err(PeError::Truncated {
+                offset: 0,
+                needed: UNWIND_INFO_HDR_SIZE,
+            });
+        }
+
+        let version = buf[0] & 0x7;
+        let flags = buf[0] >> 3;
+        let count_of_codes = buf[2];
+
+        if version != 1 && version != 2 {
+            return Err(PeError::BadUnwindInfo { rva });
+        }
+
+        // The code array is padded to an even number of slots
+        let codes_size = count_of_codes as usize * 2;
+        let trailer = UNWIND_INFO_HDR_SIZE + align_up(count_of_codes as u32, 2)? as usize * 2;
+        let trailer_size = if flags & UNW_FLAG_CHAININFO != 0 {
+            RUNTIME_FUNCTION_SIZE
+        } else if flags & (UNW_FLAG_EHANDLER | UNW_FLAG_UHANDLER) != 0 {
+            4
+        } else {
+            0
+        };
+
+        if buf.len() < trailer + trailer_size {
+            return Err(PeError::Truncated {
+                offset: 0,
+                needed: trailer + trailer_size,
+            });
+        }
+
+        // An op whose operands run past the last slot is malformed rather than truncated
+        let mut cur = ROCursor::new(&buf[UNWIND_INFO_HDR_SIZE..UNWIND_INFO_HDR_SIZE + codes_size]);
+        let mut codes = Vec::new();
+
+        while cur.remaining() > 0 {
+            codes
+                .push(read_unwind_code(&mut cur, rva).map_err(|_| PeError::BadUnwindInfo { rva })?);
+        }
+
+        let mut info = Self {
+            version,
+            flags,
+            size_of_prolog: buf[1],
+            count_of_codes,
+            frame_register: buf[3] & 0xF,
+            frame_offset: buf[3] >> 4,
+            codes,
+            handler_rva: None,
+            handler_data_rva: None,
+            chained: None,
+        };
+
+        // Chained info takes the place of the handler, the flags are never both set
+        if flags & UNW_FLAG_CHAININFO != 0 {
+            info.chained = Some(RuntimeFunction::new(&buf[trailer..])?);
+        } else if trailer_size != 0 {
+            let mut cur = ROCursor::new(&buf[trailer..]);
+            info.handler_rva = Some(cur.read_u32::<LittleEndian>()?);
+            info.handler_data_rva = Some(rva + trailer as u32 + 4);
+        }
+
+        Ok(info)
+    }
+}
+
+impl<'a> PeHeader<'a> {
+    fn exception_dir_data(&self) -> Result<Option<Ref<'_, [u8]>>, PeError> {
+        let (rva, size) = match self
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Exception)
+        {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+
+        // Other machines use different .pdata layouts
+        let machine = self.nt_hdr.file_hdr.machine.val();
+        if machine != IMAGE_FILE_MACHINE_AMD64 {
+            return Err(PeError::UnsupportedMachine { machine });
+        }
+
+        Ok(Some(self.read_at_rva(rva, size as usize)?))
+    }
+
+    // Empty when there is no exception directory
+    pub fn runtime_functions(&self) -> Result<Vec<RuntimeFunction>, PeError> {
+        match self.exception_dir_data()? {
+            Some(data) => RuntimeFunctions::iter(&data).collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // Entries are sorted by begin_addr and don't overlap, so this only reads log2(n) of them
+    pub fn runtime_function_at(&self, rva: u32) -> Result<Option<RuntimeFunction>, PeError> {
+        let data = match self.exception_dir_data()? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let mut low = 0;
+        let mut high = data.len() / RUNTIME_FUNCTION_SIZE;
+
+        while low < high {
+            let mid = (low + high) / 2;
+            let func = RuntimeFunction::new(&data[mid * RUNTIME_FUNCTION_SIZE..])?;
+
+            if rva < func.begin_addr {
+                high = mid;
+            } else if rva >= func.end_addr {
+                low = mid + 1;
+            } else {
+                return Ok(Some(func));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // An odd unwind_info_addr points at another RUNTIME_FUNCTION whose unwind info is shared
+    pub fn unwind_info(&self, func: &RuntimeFunction) -> Result<UnwindInfo, PeError> {
+        let mut rva = func.unwind_info_addr;
+
+        if rva & 1 != 0 {
+            let target = RuntimeFunction::new(&self.read_at_rva(rva & !1, RUNTIME_FUNCTION_SIZE)?)?;
+            rva = target.unwind_info_addr;
+        }
+
+        UnwindInfo::new(&self.data_at_rva(rva)?, rva)
+    }
+}
+
+//Tests
+#[cfg(feature = "std_unit_tests")]
+#[cfg(test)]
+use crate::pe::{read_test_pe, read_test_pe32, read_test_pe64};
+
+#[test]
+fn runtime_functions() {
+    let mut buf = read_test_pe64();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let funcs = pe_hdr.runtime_functions().unwrap();
+
+    assert_eq!(
+        funcs,
+        vec![
+            RuntimeFunction {
+                begin_addr: 0x1000,
+                end_addr: 0x1020,
+                unwind_info_addr: 0x2000,
+            },
+            RuntimeFunction {
+                begin_addr: 0x1020,
+                end_addr: 0x1040,
+                unwind_info_addr: 0x2010,
+            },
+            RuntimeFunction {
+                begin_addr: 0x1040,
+                end_addr: 0x1060,
+                unwind_info_addr: 0x2040,
+            },
+        ]
+    );
+
+    let mut write_buf = [0u8; 3 * RUNTIME_FUNCTION_SIZE];
+    RuntimeFunctions::write_all(&mut RWCursor::new(&mut write_buf), &funcs).unwrap();
+    assert_eq!(write_buf[..], buf[0x800..0x824]);
+
+    // A partial entry at the end
+    assert_eq!(
+        RuntimeFunctions::iter(&buf[0x800..0x810]).collect::<Vec<_>>(),
+        vec![
+            Ok(funcs[0]),
+            Err(PeError::Truncated {
+                offset: 0x10,
+                needed: 4
+            })
+        ]
+    );
+
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::new(&mut buf);
+    assert_eq!(pe_hdr.runtime_functions(), Ok(Vec::new()));
+    assert_eq!(pe_hdr.runtime_function_at(0x1000), Ok(None));
+}
+
+#[test]
+fn runtime_function_at() {
+    let mut buf = read_test_pe64();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let funcs = pe_hdr.runtime_functions().unwrap();
+
+    assert_eq!(pe_hdr.runtime_function_at(0x1000), Ok(Some(funcs[0])));
+    assert_eq!(pe_hdr.runtime_function_at(0x101F), Ok(Some(funcs[0])));
+    assert_eq!(pe_hdr.runtime_function_at(0x1020), Ok(Some(funcs[1])));
+    assert_eq!(pe_hdr.runtime_function_at(0x1050), Ok(Some(funcs[2])));
+    assert_eq!(pe_hdr.runtime_function_at(0xFFF), Ok(None));
+    assert_eq!(pe_hdr.runtime_function_at(0x1060), Ok(None));
+
+    // .pdata of another machine type isn't read as x64 entries
+    let mut buf = read_test_pe64();
+    buf[0x84..0x86].copy_from_slice(&[0x64, 0xAA]);
+    let pe_hdr = PeHeader::new(&mut buf);
+    assert_eq!(
+        pe_hdr.runtime_function_at(0x1000),
+        Err(PeError::UnsupportedMachine { machine: 0xAA64 })
+    );
+
+    let mut buf = read_test_pe32();
+    let pe_hdr = PeHeader::new(&mut buf);
+    assert_eq!(pe_hdr.runtime_functions(), Ok(Vec::new()));
+}
+
+#[test]
+fn unwind_info() {
+    let mut buf = read_test_pe64();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let funcs = pe_hdr.runtime_functions().unwrap();
+
+    let info = pe_hdr.unwind_info(&funcs[0]).unwrap();
+    assert_eq!(info.version, 1);
+    assert_eq!(info.flags, UNW_FLAG_NHANDLER);
+    assert_eq!(info.size_of_prolog, 8);
+    assert_eq!(info.count_of_codes, 3);
+    assert_eq!(info.frame_register, 5);
+    assert_eq!(info.frame_offset, 0);
+    assert_eq!(
+        info.codes,
+        vec![
+            UnwindCode {
+                code_offset: 8,
+                op: UnwindOp::SetFpReg
+            },
+            UnwindCode {
+                code_offset: 5,
+                op: UnwindOp::AllocSmall { size: 0x20 }
+            },
+            UnwindCode {
+                code_offset: 1,
+                op: UnwindOp::PushNonVol { reg: 5 }
+            },
+        ]
+    );
+    assert_eq!(info.handler_rva, None);
+    assert_eq!(info.chained, None);
+
+    let info = pe_hdr.unwind_info(&funcs[1]).unwrap();
+    assert_eq!(info.flags, UNW_FLAG_EHANDLER);
+    assert_eq!(info.size_of_prolog, 12);
+    assert_eq!(info.count_of_codes, 4);
+    assert_eq!(
+        info.codes,
+        vec![
+            UnwindCode {
+                code_offset: 12,
+                op: UnwindOp::SaveNonVol {
+                    reg: 6,
+                    offset: 0x40
+                }
+            },
+            UnwindCode {
+                code_offset: 7,
+                op: UnwindOp::AllocLarge { size: 0x128 }
+            },
+        ]
+    );
+    assert_eq_hex!(info.handler_rva, Some(0x1080));
+    assert_eq_hex!(info.handler_data_rva, Some(0x2020));
+    assert_eq_hex!(pe_hdr.read_u32_at_rva(0x2020).unwrap(), 0x12345678);
+
+    let info = pe_hdr.unwind_info(&funcs[2]).unwrap();
+    assert_eq!(info.flags, UNW_FLAG_CHAININFO);
+    assert_eq!(
+        info.codes,
+        vec![UnwindCode {
+            code_offset: 1,
+            op: UnwindOp::PushNonVol { reg: 3 }
+        }]
+    );
+    assert_eq!(info.handler_rva, None);
+    assert_eq!(info.chained, Some(funcs[0]));
+
+    // An odd address is an indirect entry, resolved through the RUNTIME_FUNCTION it points at
+    let indirect = RuntimeFunction {
+        unwind_info_addr: 0x2048 | 1,
+        ..funcs[2]
+    };
+    assert_eq!(pe_hdr.unwind_info(&indirect), pe_hdr.unwind_info(&funcs[0]));
+}
+
+#[test]
+fn unwind_info_bad() {
+    // Version 3
+    assert_eq!(
+        UnwindInfo::new(&[0x3, 0, 0, 0], 0x2000),
+        Err(PeError::BadUnwindInfo { rva: 0x2000 })
+    );
+
+    // ALLOC_LARGE needs two more slots than the one it is given
+    assert_eq!(
+        UnwindInfo::new(&[0x1, 4, 1, 0, 4, 0x11, 0, 0], 0x2000),
+        Err(PeError::BadUnwindInfo { rva: 0x2000 })
+    );
+
+    // Op 11 doesn't exist
+    assert_eq!(
+        UnwindInfo::new(&[0x1, 4, 1, 0, 4, 0x0B, 0, 0], 0x2000),
+        Err(PeError::BadUnwindInfo { rva: 0x2000 })
+    );
+
+    // Handler rva cut off
+    assert_eq!(
+        UnwindInfo::new(&[0x9, 4, 1, 0, 4, 0x50, 0, 0, 0x80, 0x10], 0x2000),
+        Err(PeError::Truncated {
+            offset: 0,
+            needed: 0xC
+        })
+    );
+
+    // Codes padded to two slots, then a UHANDLER
+    let info = UnwindInfo::new(&[0x11, 4, 1, 0, 4, 0x52, 0, 0, 0x80, 0x10, 0, 0], 0x2000).unwrap();
+    assert_eq!(info.flags, UNW_FLAG_UHANDLER);
+    assert_eq!(
+        info.codes,
+        vec![UnwindCode {
+            code_offset: 4,
+            op: UnwindOp::AllocSmall { size: 0x30 }
+        }]
+    );
+    assert_eq_hex!(info.handler_rva, Some(0x1080));
+    assert_eq_hex!(info.handler_data_rva, Some(0x200C));
+}
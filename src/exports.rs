@@ -0,0 +1,773 @@
+use crate::{
+    error::PeError,
+    nt_hdr::DataDirType,
+    pe::PeHeader,
+    sec_hdr::{IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ},
+    sections::append_section,
+    util::{IterWriteBack, ROCursor, RWCursor},
+};
+use alloc::prelude::v1::*;
+#[allow(unused_imports)]
+use assert_hex::assert_eq_hex;
+use byteorder::{ByteOrder, LittleEndian};
+use core::ops::Range;
+use zordon::prelude::*;
+
+pub const EXPORT_DIR_SIZE: usize = 0x28;
+pub const EXPORT_SEC_NAME: &[u8] = b".edata";
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ExportDirectory {
+    pub characteristics: u32,
+    pub time_data_stamp: u32,
+    pub major_ver: u16,
+    pub minor_ver: u16,
+    pub name: u32,
+    pub ordinal_base: u32,
+    pub num_of_funcs: u32,
+    pub num_of_names: u32,
+    pub addr_of_funcs: u32,
+    pub addr_of_names: u32,
+    pub addr_of_name_ordinals: u32,
+}
+
+impl ExportDirectory {
+    pub fn new(buf: &[u8]) -> Result<Self, PeError> {
+        if buf.len() < EXPORT_DIR_SIZE {
+            return Err(PeError::Truncated {
+                offset: 0,
+                needed: EXPORT_DIR_SIZE,
+            });
+        }
+
+        let mut cur = ROCursor::new(buf);
+
+        Ok(Self {
+            characteristics: cur.read_u32::<LittleEndian>()?,
+            time_data_stamp: cur.read_u32::<LittleEndian>()?,
+            major_ver: cur.read_u16::<LittleEndian>()?,
+            minor_ver: cur.read_u16::<LittleEndian>()?,
+            name: cur.read_u32::<LittleEndian>()?,
+            ordinal_base: cur.read_u32::<LittleEndian>()?,
+            num_of_funcs: cur.read_u32::<LittleEndian>()?,
+            num_of_names: cur.read_u32::<LittleEndian>()?,
+            addr_of_funcs: cur.read_u32::<LittleEndian>()?,
+            addr_of_names: cur.read_u32::<LittleEndian>()?,
+            addr_of_name_ordinals: cur.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    pub fn write(&self, buf: &mut RWCursor) -> Result<(), PeError> {
+        buf.write_u32::<LittleEndian>(self.characteristics)?;
+        buf.write_u32::<LittleEndian>(self.time_data_stamp)?;
+        buf.write_u16::<LittleEndian>(self.major_ver)?;
+        buf.write_u16::<LittleEndian>(self.minor_ver)?;
+        buf.write_u32::<LittleEndian>(self.name)?;
+        buf.write_u32::<LittleEndian>(self.ordinal_base)?;
+        buf.write_u32::<LittleEndian>(self.num_of_funcs)?;
+        buf.write_u32::<LittleEndian>(self.num_of_names)?;
+        buf.write_u32::<LittleEndian>(self.addr_of_funcs)?;
+        buf.write_u32::<LittleEndian>(self.addr_of_names)?;
+        buf.write_u32::<LittleEndian>(self.addr_of_name_ordinals)
+    }
+}
+
+// Export Address Table entries, an RVA into the image or to a forwarder string. Ends with the
+// buffer, or with an error if it stops part way through an entry
+pub struct ExportAddressIter<'a> {
+    cur: ROCursor<'a>,
+    done: bool,
+}
+
+impl<'a> ExportAddressIter<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            cur: ROCursor::new(buf),
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for ExportAddressIter<'a> {
+    type Item = Result<u32, PeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.cur.remaining() == 0 {
+            return None;
+        }
+
+        let r = self.cur.read_u32::<LittleEndian>();
+        self.done = r.is_err();
+        Some(r)
+    }
+}
+
+pub struct ExportAddresses;
+
+impl<'a> IterWriteBack<'a> for ExportAddresses {
+    type Iter = ExportAddressIter<'a>;
+    type Output = u32;
+
+    fn iter(buf: &'a [u8]) -> Self::Iter {
+        ExportAddressIter::new(buf)
+    }
+
+    fn write_single(buf: &mut RWCursor, rva: &Self::Output) -> Result<(), PeError> {
+        buf.write_u32::<LittleEndian>(*rva)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub ordinal: u16,
+    pub name: Option<String>,
+    pub rva: u32,
+    pub forwarder: Option<String>, // e.g. "NTDLL.RtlFoo", rva then points at this string
+}
+
+pub struct ExportIter<'p, 'a> {
+    pe_hdr: &'p PeHeader<'a>,
+    dir: ExportDirectory,
+    dir_range: Range<u32>,
+    addrs: Vec<u32>,
+    name_rvas: Vec<Option<u32>>, // Indexed like addrs
+    index: usize,
+}
+
+impl<'p, 'a> ExportIter<'p, 'a> {
+    fn export_at(&self, index: usize) -> Result<Export, PeError> {
+        let rva = self.addrs[index];
+
+        let name = match self.name_rvas[index] {
+            Some(name_rva) => Some(self.pe_hdr.read_c_string_at_rva(name_rva)?),
+            None => None,
+        };
+
+        let forwarder = if self.dir_range.contains(&rva) {
+            Some(self.pe_hdr.read_c_string_at_rva(rva)?)
+        } else {
+            None
+        };
+
+        // Ordinals are 16 bits, a base from the file can put them past that
+        let ordinal = self
+            .dir
+            .ordinal_base
+            .checked_add(index as u32)
+            .filter(|o| *o <= u16::MAX as u32)
+            .ok_or(PeError::ExportOrdinalOverflow)?;
+
+        Ok(Export {
+            ordinal: ordinal as u16,
+            name,
+            rva,
+            forwarder,
+        })
+    }
+}
+
+impl<'p, 'a> Iterator for ExportIter<'p, 'a> {
+    type Item = Result<Export, PeError>;
+
+    // Unused address table slots are skipped
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.addrs.len() {
+            let index = self.index;
+            self.index += 1;
+
+            if self.addrs[index] != 0 {
+                return Some(self.export_at(index));
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a> PeHeader<'a> {
+    pub fn export_directory(&self) -> Result<Option<ExportDirectory>, PeError> {
+        match self
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Export)
+        {
+            Some((rva, _)) => Ok(Some(ExportDirectory::new(&self.data_at_rva(rva)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn exports(&self) -> Result<ExportIter<'_, 'a>, PeError> {
+        let (dir_rva, dir_size) = self
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Export)
+            .unwrap_or_default();
+
+        let dir = match self.export_directory()? {
+            Some(d) => d,
+            None => {
+                return Ok(ExportIter {
+                    pe_hdr: self,
+                    dir: ExportDirectory::default(),
+                    dir_range: 0..0,
+                    addrs: Vec::new(),
+                    name_rvas: Vec::new(),
+                    index: 0,
+                })
+            }
+        };
+
+        let addrs: Vec<u32> = ExportAddresses::iter(&self.data_at_rva(dir.addr_of_funcs)?)
+            .take(dir.num_of_funcs as usize)
+            .collect::<Result<_, _>>()?;
+        let mut name_rvas: Vec<Option<u32>> = addrs.iter().map(|_| None).collect();
+
+        for (name_rva, index) in self.export_name_table(&dir)? {
+            if let Some(n) = name_rvas.get_mut(index as usize) {
+                *n = Some(name_rva);
+            }
+        }
+
+        Ok(ExportIter {
+            pe_hdr: self,
+            dir,
+            dir_range: dir_rva..dir_rva.saturating_add(dir_size),
+            addrs,
+            name_rvas,
+            index: 0,
+        })
+    }
+
+    // Binary search of the name pointer table, which is sorted for the loader to do the same
+    pub fn export_by_name(&self, name: &str) -> Result<Option<Export>, PeError> {
+        let exports = self.exports()?;
+        let names = self.export_name_table(&exports.dir)?;
+
+        let (mut lo, mut hi) = (0, names.len());
+
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let (name_rva, index) = names[mid];
+            let mid_name = self.read_c_string_at_rva(name_rva)?;
+
+            match mid_name.as_str().cmp(name) {
+                core::cmp::Ordering::Less => lo = mid + 1,
+                core::cmp::Ordering::Greater => hi = mid,
+                core::cmp::Ordering::Equal if (index as usize) < exports.addrs.len() => {
+                    return exports.export_at(index as usize).map(Some)
+                }
+                core::cmp::Ordering::Equal => return Ok(None),
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn export_by_ordinal(&self, ordinal: u16) -> Result<Option<Export>, PeError> {
+        let exports = self.exports()?;
+        let index = (ordinal as u32).wrapping_sub(exports.dir.ordinal_base) as usize;
+
+        match exports.addrs.get(index) {
+            Some(&rva) if rva != 0 => exports.export_at(index).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    // (name RVA, address table index) pairs in name pointer table order
+    fn export_name_table(&self, dir: &ExportDirectory) -> Result<Vec<(u32, u16)>, PeError> {
+        if dir.num_of_names == 0 {
+            return Ok(Vec::new());
+        }
+
+        let name_ptrs = self.data_at_rva(dir.addr_of_names)?;
+        let ordinals = self.data_at_rva(dir.addr_of_name_ordinals)?;
+        let num_of_names = (dir.num_of_names as usize)
+            .min(name_ptrs.len() / 4)
+            .min(ordinals.len() / 2);
+
+        Ok((0..num_of_names)
+            .map(|i| {
+                (
+                    LittleEndian::read_u32(&name_ptrs[i * 4..]),
+                    LittleEndian::read_u16(&ordinals[i * 2..]),
+                )
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExportTarget {
+    Rva(u32),
+    Forwarder(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportEntry {
+    pub ordinal: u16,
+    pub name: Option<String>,
+    pub target: ExportTarget,
+}
+
+// Regenerates an export directory. Ordinals are kept as given, so removing an export leaves an
+// unused address table slot rather than renumbering the exports after it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportBuilder {
+    pub dll_name: String,
+    pub characteristics: u32,
+    pub time_data_stamp: u32,
+    pub major_ver: u16,
+    pub minor_ver: u16,
+    pub entries: Vec<ExportEntry>,
+}
+
+impl ExportBuilder {
+    pub fn new(dll_name: &str) -> Self {
+        Self {
+            dll_name: dll_name.to_string(),
+            characteristics: 0,
+            time_data_stamp: 0,
+            major_ver: 0,
+            minor_ver: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn from_pe(pe_hdr: &PeHeader) -> Result<Self, PeError> {
+        let dir = match pe_hdr.export_directory()? {
+            Some(d) => d,
+            None => {
+                return Err(PeError::DataDirNotPresent {
+                    dir: DataDirType::Export,
+                })
+            }
+        };
+
+        let mut entries = Vec::new();
+
+        for e in pe_hdr.exports()? {
+            let e = e?;
+
+            entries.push(ExportEntry {
+                ordinal: e.ordinal,
+                name: e.name,
+                target: match e.forwarder {
+                    Some(f) => ExportTarget::Forwarder(f),
+                    None => ExportTarget::Rva(e.rva),
+                },
+            });
+        }
+
+        Ok(Self {
+            dll_name: pe_hdr.read_c_string_at_rva(dir.name)?,
+            characteristics: dir.characteristics,
+            time_data_stamp: dir.time_data_stamp,
+            major_ver: dir.major_ver,
+            minor_ver: dir.minor_ver,
+            entries,
+        })
+    }
+
+    // Returns the ordinal assigned to the new export, one past the highest used so far
+    pub fn add(&mut self, name: &str, target: ExportTarget) -> Result<u16, PeError> {
+        if self.find(name).is_some() {
+            return Err(PeError::DuplicateExport);
+        }
+
+        let ordinal = match self.entries.iter().map(|e| e.ordinal).max() {
+            Some(o) => o.checked_add(1).ok_or(PeError::ExportOrdinalOverflow)?,
+            None => 1,
+        };
+
+        self.entries.push(ExportEntry {
+            ordinal,
+            name: Some(name.to_string()),
+            target,
+        });
+
+        Ok(ordinal)
+    }
+
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<(), PeError> {
+        if self.find(new_name).is_some() {
+            return Err(PeError::DuplicateExport);
+        }
+
+        let i = self.find(name).ok_or(PeError::ExportNotFound)?;
+        self.entries[i].name = Some(new_name.to_string());
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<ExportEntry, PeError> {
+        let i = self.find(name).ok_or(PeError::ExportNotFound)?;
+        Ok(self.entries.remove(i))
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|e| e.name.as_deref() == Some(name))
+    }
+
+    // Serialises the directory, address, name pointer and ordinal tables followed by the
+    // strings, for placement at virt_addr. Forwarder strings have to be inside the export
+    // directory for the loader to treat them as such, so the size covers everything
+    pub fn build(&self, virt_addr: u32) -> Vec<u8> {
+        let ordinal_base = self.entries.iter().map(|e| e.ordinal).min().unwrap_or(1);
+        let num_of_funcs = match self.entries.iter().map(|e| e.ordinal).max() {
+            Some(max) => (max - ordinal_base) as usize + 1,
+            None => 0,
+        };
+
+        let mut named: Vec<&ExportEntry> =
+            self.entries.iter().filter(|e| e.name.is_some()).collect();
+        named.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let addr_of_funcs = EXPORT_DIR_SIZE;
+        let addr_of_names = addr_of_funcs + num_of_funcs * 4;
+        let addr_of_name_ordinals = addr_of_names + named.len() * 4;
+        let strings_offset = addr_of_name_ordinals + named.len() * 2;
+
+        let mut strings: Vec<u8> = Vec::new();
+        let mut add_string = |s: &str| {
+            let rva = virt_addr + (strings_offset + strings.len()) as u32;
+            strings.extend_from_slice(s.as_bytes());
+            strings.push(0);
+            rva
+        };
+
+        let dll_name_rva = add_string(&self.dll_name);
+
+        let name_rvas: Vec<u32> = named
+            .iter()
+            .map(|e| add_string(e.name.as_ref().unwrap()))
+            .collect();
+
+        let mut addrs = vec![0u32; num_of_funcs];
+
+        for e in self.entries.iter() {
+            addrs[(e.ordinal - ordinal_base) as usize] = match &e.target {
+                ExportTarget::Rva(rva) => *rva,
+                ExportTarget::Forwarder(f) => add_string(f),
+            };
+        }
+
+        let mut data = vec![0u8; strings_offset];
+        data.extend_from_slice(&strings);
+
+        let dir = ExportDirectory {
+            characteristics: self.characteristics,
+            time_data_stamp: self.time_data_stamp,
+            major_ver: self.major_ver,
+            minor_ver: self.minor_ver,
+            name: dll_name_rva,
+            ordinal_base: ordinal_base as u32,
+            num_of_funcs: num_of_funcs as u32,
+            num_of_names: named.len() as u32,
+            addr_of_funcs: virt_addr + addr_of_funcs as u32,
+            addr_of_names: virt_addr + addr_of_names as u32,
+            addr_of_name_ordinals: virt_addr + addr_of_name_ordinals as u32,
+        };
+
+        // The tables are sized from the entries above so the writes can't run out of room
+        let mut cur = RWCursor::new(&mut data[..strings_offset]);
+        dir.write(&mut cur).unwrap();
+        ExportAddresses::write_all(&mut cur, &addrs).unwrap();
+
+        for rva in name_rvas.iter() {
+            cur.write_u32::<LittleEndian>(*rva).unwrap();
+        }
+
+        for e in named.iter() {
+            cur.write_u16::<LittleEndian>(e.ordinal - ordinal_base)
+                .unwrap();
+        }
+
+        data
+    }
+
+    // Writes the export directory to a new section and points the export data directory at it
+    pub fn write(&self, buf: &[u8]) -> Result<Vec<u8>, PeError> {
+        let mut out = buf.to_vec();
+
+        let sec_virt_addr = {
+            let pe_hdr = PeHeader::try_new(&mut out)?;
+
+            if pe_hdr.nt_hdr.opt_hdr.data_dirs.export.is_none() {
+                return Err(PeError::DataDirNotPresent {
+                    dir: DataDirType::Export,
+                });
+            }
+
+            pe_hdr.next_sec_virt_addr()?
+        };
+
+        let data = self.build(sec_virt_addr);
+        let mut out = append_section(
+            &out,
+            EXPORT_SEC_NAME,
+            IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+            &data,
+        )?;
+
+        {
+            let mut pe_hdr = PeHeader::try_new(&mut out)?;
+
+            if let Some(export_dir) = pe_hdr.nt_hdr.opt_hdr.data_dirs.export.as_mut() {
+                export_dir.virt_addr.set(sec_virt_addr);
+                export_dir.size.set(data.len() as u32);
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(feature = "std_unit_tests")]
+#[cfg(test)]
+use crate::pe::{read_test_pe, read_test_pe32};
+
+#[test]
+fn export_directory() {
+    let mut buf = read_test_pe32();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let dir = pe_hdr.export_directory().unwrap().unwrap();
+
+    assert_eq_hex!(dir.name, 0x2160);
+    assert_eq_hex!(dir.ordinal_base, 1);
+    assert_eq_hex!(dir.num_of_funcs, 4);
+    assert_eq_hex!(dir.num_of_names, 3);
+    assert_eq_hex!(dir.addr_of_funcs, 0x2130);
+    assert_eq_hex!(dir.addr_of_names, 0x2140);
+    assert_eq_hex!(dir.addr_of_name_ordinals, 0x2150);
+
+    let write_buf = &mut [0 as u8; EXPORT_DIR_SIZE] as &mut [u8];
+    dir.write(&mut RWCursor::new(write_buf)).unwrap();
+    assert_eq!(*write_buf, buf[0x500..0x528]);
+}
+
+#[test]
+fn export_addresses_writeback() {
+    let mut buf = read_test_pe32();
+    let addrs: Vec<u32> = ExportAddresses::iter(&buf[0x530..0x540])
+        .map(|a| a.unwrap())
+        .collect();
+
+    assert_eq_hex!(addrs, [0x1000, 0x1010, 0x2190, 0x1020]);
+
+    let write_buf = &mut [0 as u8; 0x10] as &mut [u8];
+    ExportAddresses::write_all(&mut RWCursor::new(write_buf), &addrs).unwrap();
+    assert_eq!(
+        ExportAddresses::iter(&buf[0x530..0x536]).collect::<Vec<_>>(),
+        [
+            Ok(0x1000),
+            Err(PeError::Truncated {
+                offset: 4,
+                needed: 4
+            })
+        ]
+    );
+    assert_eq!(*write_buf, buf[0x530..0x540]);
+
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    assert!(pe_hdr.export_directory().unwrap().is_some());
+}
+
+#[test]
+fn exports_iter() {
+    let mut buf = read_test_pe32();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let exports: Vec<Export> = pe_hdr.exports().unwrap().map(|e| e.unwrap()).collect();
+
+    assert_eq!(
+        exports,
+        [
+            Export {
+                ordinal: 1,
+                name: Some("Alpha".to_string()),
+                rva: 0x1000,
+                forwarder: None
+            },
+            Export {
+                ordinal: 2,
+                name: Some("Beta".to_string()),
+                rva: 0x1010,
+                forwarder: None
+            },
+            Export {
+                ordinal: 3,
+                name: Some("Gamma".to_string()),
+                rva: 0x2190,
+                forwarder: Some("NTDLL.RtlFoo".to_string())
+            },
+            Export {
+                ordinal: 4,
+                name: None,
+                rva: 0x1020,
+                forwarder: None
+            },
+        ]
+    );
+}
+
+#[test]
+fn exports_ordinal_overflow() {
+    let mut buf = read_test_pe32();
+    LittleEndian::write_u32(&mut buf[0x510..], 0xFFFE);
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let exports: Vec<_> = pe_hdr.exports().unwrap().collect();
+
+    assert_eq!(exports[0].as_ref().unwrap().ordinal, 0xFFFE);
+    assert_eq!(exports[1].as_ref().unwrap().ordinal, 0xFFFF);
+    assert_eq!(exports[2], Err(PeError::ExportOrdinalOverflow));
+
+    LittleEndian::write_u32(&mut buf[0x510..], 0xFFFF_FFFF);
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    assert_eq!(
+        pe_hdr.exports().unwrap().nth(1),
+        Some(Err(PeError::ExportOrdinalOverflow))
+    );
+}
+
+#[test]
+fn exports_none() {
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(pe_hdr.export_directory().unwrap(), None);
+    assert_eq!(pe_hdr.exports().unwrap().count(), 0);
+    assert_eq!(pe_hdr.export_by_name("Alpha").unwrap(), None);
+    assert_eq!(pe_hdr.export_by_ordinal(1).unwrap(), None);
+}
+
+#[test]
+fn export_lookups() {
+    let mut buf = read_test_pe32();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq_hex!(pe_hdr.export_by_name("Alpha").unwrap().unwrap().rva, 0x1000);
+    assert_eq_hex!(pe_hdr.export_by_name("Beta").unwrap().unwrap().ordinal, 2);
+    assert_eq!(
+        pe_hdr.export_by_name("Gamma").unwrap().unwrap().forwarder,
+        Some("NTDLL.RtlFoo".to_string())
+    );
+    assert_eq!(pe_hdr.export_by_name("Delta").unwrap(), None);
+    assert_eq!(pe_hdr.export_by_name("").unwrap(), None);
+
+    assert_eq!(
+        pe_hdr.export_by_ordinal(2).unwrap().unwrap().name,
+        Some("Beta".to_string())
+    );
+    assert_eq_hex!(pe_hdr.export_by_ordinal(4).unwrap().unwrap().rva, 0x1020);
+    assert_eq!(pe_hdr.export_by_ordinal(0).unwrap(), None);
+    assert_eq!(pe_hdr.export_by_ordinal(5).unwrap(), None);
+}
+
+#[test]
+fn export_builder_from_pe() {
+    let mut buf = read_test_pe32();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let mut builder = ExportBuilder::from_pe(&pe_hdr).unwrap();
+
+    assert_eq!(builder.dll_name, "test_pe32.dll");
+    assert_eq!(builder.entries.len(), 4);
+    assert_eq!(
+        builder.entries[2],
+        ExportEntry {
+            ordinal: 3,
+            name: Some("Gamma".to_string()),
+            target: ExportTarget::Forwarder("NTDLL.RtlFoo".to_string())
+        }
+    );
+
+    assert_eq!(
+        builder.add("Alpha", ExportTarget::Rva(0x1000)),
+        Err(PeError::DuplicateExport)
+    );
+    assert_eq!(builder.add("Delta", ExportTarget::Rva(0x1010)), Ok(5));
+    assert_eq!(
+        builder.rename("Beta", "Gamma"),
+        Err(PeError::DuplicateExport)
+    );
+    assert_eq!(builder.rename("Zeta", "Eta"), Err(PeError::ExportNotFound));
+    assert_eq!(builder.remove("Zeta"), Err(PeError::ExportNotFound));
+
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    assert_eq!(
+        ExportBuilder::from_pe(&pe_hdr),
+        Err(PeError::DataDirNotPresent {
+            dir: DataDirType::Export
+        })
+    );
+}
+
+#[test]
+fn export_builder_write() {
+    let mut builder = ExportBuilder::new("proxy.dll");
+
+    assert_eq!(builder.add("Open", ExportTarget::Rva(0x1000)), Ok(1));
+    assert_eq!(builder.add("Close", ExportTarget::Rva(0x1010)), Ok(2));
+    assert_eq!(
+        builder.add("Read", ExportTarget::Forwarder("real.Read".to_string())),
+        Ok(3)
+    );
+    assert_eq!(builder.add("Write", ExportTarget::Rva(0x1020)), Ok(4));
+    builder.rename("Close", "Shut").unwrap();
+    builder.remove("Open").unwrap();
+
+    let buf = read_test_pe();
+    let mut out = builder.write(&buf).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 6);
+    assert_eq!(*pe_hdr.sec_hdrs[5].name.as_ref(), b".edata\0\0");
+    assert_eq!(
+        pe_hdr
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Export),
+        Some((0x6000, 0x6A))
+    );
+
+    let dir = pe_hdr.export_directory().unwrap().unwrap();
+    assert_eq_hex!(dir.ordinal_base, 2);
+    assert_eq_hex!(dir.num_of_funcs, 3);
+    assert_eq_hex!(dir.num_of_names, 3);
+    assert_eq!(pe_hdr.read_c_string_at_rva(dir.name).unwrap(), "proxy.dll");
+
+    let exports: Vec<Export> = pe_hdr.exports().unwrap().map(|e| e.unwrap()).collect();
+    assert_eq!(
+        exports,
+        [
+            Export {
+                ordinal: 2,
+                name: Some("Shut".to_string()),
+                rva: 0x1010,
+                forwarder: None
+            },
+            Export {
+                ordinal: 3,
+                name: Some("Read".to_string()),
+                rva: 0x6060,
+                forwarder: Some("real.Read".to_string())
+            },
+            Export {
+                ordinal: 4,
+                name: Some("Write".to_string()),
+                rva: 0x1020,
+                forwarder: None
+            },
+        ]
+    );
+
+    // Names are sorted for the binary search
+    assert_eq_hex!(pe_hdr.export_by_name("Read").unwrap().unwrap().ordinal, 3);
+    assert_eq_hex!(pe_hdr.export_by_name("Shut").unwrap().unwrap().ordinal, 2);
+    assert_eq_hex!(pe_hdr.export_by_name("Write").unwrap().unwrap().ordinal, 4);
+    assert_eq!(pe_hdr.export_by_name("Open").unwrap(), None);
+}
@@ -1,8 +1,21 @@
-use crate::util::{IterWriteBack, ROCursor, RWCursor};
+use crate::{
+    error::PeError,
+    nt_hdr::DataDirType,
+    pe::PeHeader,
+    sec_hdr::{IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE},
+    sections::append_section,
+    util::{align_up, IterWriteBack, ROCursor, RWCursor},
+};
+use alloc::prelude::v1::*;
 #[allow(unused_imports)]
 use assert_hex::assert_eq_hex;
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian};
+use zordon::prelude::*;
+
+pub const IMPORT_DESC_SIZE: usize = 0x14;
+pub const IMPORT_SEC_NAME: &[u8] = b".idata2";
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ImportDescriptor {
     pub original_first_thunk: u32,
     pub time_data_stamp: u32,
@@ -13,33 +26,46 @@ pub struct ImportDescriptor {
 
 pub struct ImportDescriptorIter<'a> {
     cur: ROCursor<'a>,
+    done: bool,
 }
 
 impl<'a> ImportDescriptorIter<'a> {
     pub fn new(buf: &'a [u8]) -> Self {
         Self {
             cur: ROCursor::new(buf),
+            done: false,
         }
     }
+
+    fn read_next(&mut self) -> Result<Option<ImportDescriptor>, PeError> {
+        let import_desc = ImportDescriptor {
+            original_first_thunk: self.cur.read_u32::<LittleEndian>()?,
+            time_data_stamp: self.cur.read_u32::<LittleEndian>()?,
+            forwarder_chain: self.cur.read_u32::<LittleEndian>()?,
+            name: self.cur.read_u32::<LittleEndian>()?,
+            first_thunk: self.cur.read_u32::<LittleEndian>()?,
+        };
+
+        if import_desc.original_first_thunk == 0 && import_desc.first_thunk == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(import_desc))
+    }
 }
 
 impl<'a> Iterator for ImportDescriptorIter<'a> {
-    type Item = ImportDescriptor;
+    type Item = Result<ImportDescriptor, PeError>;
 
+    // Ends at the null descriptor, or with an error if the buffer runs out before it
     fn next(&mut self) -> Option<Self::Item> {
-        let original_first_thunk = self.cur.read_u32::<LittleEndian>();
-
-        if original_first_thunk == 0 {
+        if self.done {
             return None;
         }
 
-        Some(ImportDescriptor {
-            original_first_thunk,
-            time_data_stamp: self.cur.read_u32::<LittleEndian>(),
-            forwarder_chain: self.cur.read_u32::<LittleEndian>(),
-            name: self.cur.read_u32::<LittleEndian>(),
-            first_thunk: self.cur.read_u32::<LittleEndian>(),
-        })
+        let r = self.read_next().transpose();
+        self.done = !matches!(r, Some(Ok(_)));
+        r
     }
 }
 
@@ -53,13 +79,277 @@ impl<'a> IterWriteBack<'a> for ImportDescriptors {
         ImportDescriptorIter::into_iter(ImportDescriptorIter::new(buf))
     }
 
-    fn write_single(buf: &mut RWCursor, import_desc: &Self::Output) {
-        buf.write_u32::<LittleEndian>(import_desc.original_first_thunk);
-        buf.write_u32::<LittleEndian>(import_desc.time_data_stamp);
-        buf.write_u32::<LittleEndian>(import_desc.forwarder_chain);
-        buf.write_u32::<LittleEndian>(import_desc.name);
-        buf.write_u32::<LittleEndian>(import_desc.first_thunk);
+    fn write_single(buf: &mut RWCursor, import_desc: &Self::Output) -> Result<(), PeError> {
+        buf.write_u32::<LittleEndian>(import_desc.original_first_thunk)?;
+        buf.write_u32::<LittleEndian>(import_desc.time_data_stamp)?;
+        buf.write_u32::<LittleEndian>(import_desc.forwarder_chain)?;
+        buf.write_u32::<LittleEndian>(import_desc.name)?;
+        buf.write_u32::<LittleEndian>(import_desc.first_thunk)
+    }
+}
+
+// A single Import Lookup Table (or Import Address Table before binding) entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportLookup {
+    ByOrdinal(u16),
+    ByName(u32), // RVA of the hint/name entry
+}
+
+pub struct ImportLookupIter<'a> {
+    cur: ROCursor<'a>,
+    pe32_plus: bool,
+    done: bool,
+}
+
+impl<'a> ImportLookupIter<'a> {
+    pub fn new(buf: &'a [u8], pe32_plus: bool) -> Self {
+        Self {
+            cur: ROCursor::new(buf),
+            pe32_plus,
+            done: false,
+        }
+    }
+
+    fn read_next(&mut self) -> Result<Option<ImportLookup>, PeError> {
+        let (thunk, by_ordinal) = if self.pe32_plus {
+            let thunk = self.cur.read_u64::<LittleEndian>()?;
+            (thunk, thunk & (1 << 63) != 0)
+        } else {
+            let thunk = self.cur.read_u32::<LittleEndian>()? as u64;
+            (thunk, thunk & (1 << 31) != 0)
+        };
+
+        if thunk == 0 {
+            return Ok(None);
+        }
+
+        if by_ordinal {
+            Ok(Some(ImportLookup::ByOrdinal(thunk as u16)))
+        } else {
+            Ok(Some(ImportLookup::ByName(thunk as u32 & 0x7FFF_FFFF)))
+        }
+    }
+}
+
+impl<'a> Iterator for ImportLookupIter<'a> {
+    type Item = Result<ImportLookup, PeError>;
+
+    // Ends at the null thunk, or with an error if the buffer runs out before it
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let r = self.read_next().transpose();
+        self.done = !matches!(r, Some(Ok(_)));
+        r
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportEntry {
+    ByName { hint: u16, name: String },
+    ByOrdinal(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportFunction {
+    pub entry: ImportEntry,
+    pub iat_rva: u32, // RVA of the IAT slot the loader writes the address to
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub dll_name: String,
+    pub descriptor: ImportDescriptor,
+    pub functions: Vec<ImportFunction>,
+}
+
+impl<'a> PeHeader<'a> {
+    pub fn import_descriptors(&self) -> Result<Vec<ImportDescriptor>, PeError> {
+        let (import_rva, _) = match self
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Import)
+        {
+            Some(d) => d,
+            None => return Ok(Vec::new()),
+        };
+
+        let data = self.data_at_rva(import_rva)?;
+
+        ImportDescriptors::iter(&data).collect()
+    }
+
+    pub fn imports(&self) -> Result<Vec<Import>, PeError> {
+        let mut imports = Vec::new();
+
+        for descriptor in self.import_descriptors()? {
+            let dll_name = self.read_c_string_at_rva(descriptor.name)?;
+
+            // Some linkers leave the ILT empty, the unbound IAT holds the same entries
+            let ilt_rva = if descriptor.original_first_thunk != 0 {
+                descriptor.original_first_thunk
+            } else {
+                descriptor.first_thunk
+            };
+
+            let thunk_size = if self.is_pe32_plus() { 8 } else { 4 };
+            let ilt = self.data_at_rva(ilt_rva)?;
+            let mut functions = Vec::new();
+
+            for (i, lookup) in ImportLookupIter::new(&ilt, self.is_pe32_plus()).enumerate() {
+                let entry = match lookup? {
+                    ImportLookup::ByOrdinal(ordinal) => ImportEntry::ByOrdinal(ordinal),
+                    ImportLookup::ByName(rva) => ImportEntry::ByName {
+                        hint: self.read_u16_at_rva(rva)?,
+                        name: self.read_c_string_at_rva(
+                            rva.checked_add(2).ok_or(PeError::RvaNotMapped { rva })?,
+                        )?,
+                    },
+                };
+
+                let iat_rva = descriptor
+                    .first_thunk
+                    .checked_add((i * thunk_size) as u32)
+                    .ok_or(PeError::RvaNotMapped {
+                        rva: descriptor.first_thunk,
+                    })?;
+
+                functions.push(ImportFunction { entry, iat_rva });
+            }
+
+            imports.push(Import {
+                dll_name,
+                descriptor,
+                functions,
+            });
+        }
+
+        Ok(imports)
+    }
+}
+
+// Adds an import of functions from dll in a new section. The descriptor array is rebuilt in the
+// new section, but the existing descriptors keep their original ILTs, IATs and names so code
+// referencing the existing IAT slots still works
+pub fn add_import(buf: &[u8], dll: &str, functions: &[ImportEntry]) -> Result<Vec<u8>, PeError> {
+    let mut out = buf.to_vec();
+
+    let (mut descriptors, pe32_plus, sec_virt_addr, bound) = {
+        let pe_hdr = PeHeader::try_new(&mut out)?;
+        let data_dirs = &pe_hdr.nt_hdr.opt_hdr.data_dirs;
+
+        if data_dirs.import.is_none() {
+            return Err(PeError::DataDirNotPresent {
+                dir: DataDirType::Import,
+            });
+        }
+
+        (
+            pe_hdr.import_descriptors()?,
+            pe_hdr.is_pe32_plus(),
+            pe_hdr.next_sec_virt_addr()?,
+            data_dirs.rva_and_size(DataDirType::BoundImport).is_some(),
+        )
+    };
+
+    // Binding information is invalidated by the new descriptor array, so the loader has to
+    // resolve everything itself
+    if bound {
+        for d in descriptors.iter_mut() {
+            d.time_data_stamp = 0;
+            d.forwarder_chain = 0;
+        }
+    }
+
+    let thunk_size = if pe32_plus { 8 } else { 4 };
+    let descs_size = (descriptors.len() + 2) * IMPORT_DESC_SIZE;
+    let thunks_size = (functions.len() + 1) * thunk_size;
+    let ilt_offset = align_up(descs_size as u32, 8)? as usize;
+    let iat_offset = ilt_offset + thunks_size;
+
+    let mut data = vec![0u8; iat_offset + thunks_size];
+
+    for (i, f) in functions.iter().enumerate() {
+        let thunk = match f {
+            ImportEntry::ByOrdinal(ordinal) if pe32_plus => (1 << 63) | *ordinal as u64,
+            ImportEntry::ByOrdinal(ordinal) => (1 << 31) | *ordinal as u64,
+            ImportEntry::ByName { hint, name } => {
+                let hint_name_rva = sec_virt_addr + data.len() as u32;
+
+                data.extend_from_slice(&hint.to_le_bytes());
+                data.extend_from_slice(name.as_bytes());
+                data.push(0);
+
+                if data.len() % 2 != 0 {
+                    data.push(0);
+                }
+
+                hint_name_rva as u64
+            }
+        };
+
+        for offset in [ilt_offset, iat_offset].iter() {
+            let thunk_buf = &mut data[offset + i * thunk_size..];
+
+            if pe32_plus {
+                LittleEndian::write_u64(thunk_buf, thunk);
+            } else {
+                LittleEndian::write_u32(thunk_buf, thunk as u32);
+            }
+        }
+    }
+
+    let name_rva = sec_virt_addr + data.len() as u32;
+    data.extend_from_slice(dll.as_bytes());
+    data.push(0);
+
+    descriptors.push(ImportDescriptor {
+        original_first_thunk: sec_virt_addr + ilt_offset as u32,
+        time_data_stamp: 0,
+        forwarder_chain: 0,
+        name: name_rva,
+        first_thunk: sec_virt_addr + iat_offset as u32,
+    });
+
+    // The null descriptor after the array is left zeroed
+    ImportDescriptors::write_all(&mut RWCursor::new(&mut data[..descs_size]), &descriptors)?;
+
+    let mut out = append_section(
+        &out,
+        IMPORT_SEC_NAME,
+        IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+        &data,
+    )?;
+
+    {
+        let mut pe_hdr = PeHeader::try_new(&mut out)?;
+        let data_dirs = &mut pe_hdr.nt_hdr.opt_hdr.data_dirs;
+
+        if let Some(import_dir) = data_dirs.import.as_mut() {
+            import_dir.virt_addr.set(sec_virt_addr);
+            import_dir.size.set(descs_size as u32);
+        }
+
+        // An existing IAT directory is left alone. The loader reprotects its range while binding,
+        // and widening it to take in the new IAT would cover every section in between. Without
+        // one it is pointed at the new IAT
+        if data_dirs.rva_and_size(DataDirType::Iat).is_none() {
+            if let Some(iat_dir) = data_dirs.iat.as_mut() {
+                iat_dir.virt_addr.set(sec_virt_addr + iat_offset as u32);
+                iat_dir.size.set(thunks_size as u32);
+            }
+        }
+
+        if let Some(bound_import_dir) = data_dirs.bound_import.as_mut() {
+            bound_import_dir.virt_addr.set(0);
+            bound_import_dir.size.set(0);
+        }
     }
+
+    Ok(out)
 }
 
 #[allow(dead_code)]
@@ -71,15 +361,15 @@ const IMPORT_DESC_TESTDATA: [u8; 44] = [
 
 #[cfg(feature = "std_unit_tests")]
 #[cfg(test)]
-use alloc::prelude::v1::*;
+use crate::pe::{read_test_pe, read_test_pe32};
 
 #[test]
 fn import_descriptor_iter() {
     let import_descs_iter = ImportDescriptorIter::new(&IMPORT_DESC_TESTDATA);
     let mut import_descs: Vec<ImportDescriptor> = Vec::with_capacity(2);
 
-    for i in import_descs_iter.into_iter() {
-        import_descs.push(i);
+    for i in import_descs_iter.take(2) {
+        import_descs.push(i.unwrap());
     }
 
     assert_eq_hex!(import_descs[0].original_first_thunk, 0x3140);
@@ -102,11 +392,284 @@ fn import_descriptor_writeback() {
     let mut import_descs_write_buf = RWCursor::new(write_buf);
     let mut import_descriptors: Vec<ImportDescriptor> = Vec::with_capacity(2);
 
-    for r in import_descs_iter.into_iter() {
-        import_descriptors.push(r);
+    for r in import_descs_iter.take(2) {
+        import_descriptors.push(r.unwrap());
     }
 
-    ImportDescriptors::write_all(&mut import_descs_write_buf, &import_descriptors);
+    ImportDescriptors::write_all(&mut import_descs_write_buf, &import_descriptors).unwrap();
 
     assert_eq!(IMPORT_DESC_TESTDATA, import_descs_write_buf.buf);
 }
+
+#[test]
+fn import_descriptor_iter_truncated() {
+    let descs: Vec<Result<ImportDescriptor, PeError>> =
+        ImportDescriptorIter::new(&IMPORT_DESC_TESTDATA).collect();
+
+    assert_eq!(descs.len(), 3);
+    assert_eq!(
+        descs[2],
+        Err(PeError::Truncated {
+            offset: 0x2C,
+            needed: 4
+        })
+    );
+
+    let lookups: Vec<Result<ImportLookup, PeError>> =
+        ImportLookupIter::new(&[0xA0, 0x20, 0, 0, 0xF4, 0x01], false).collect();
+
+    assert_eq!(
+        lookups,
+        [
+            Ok(ImportLookup::ByName(0x20A0)),
+            Err(PeError::Truncated {
+                offset: 4,
+                needed: 4
+            })
+        ]
+    );
+}
+
+#[test]
+fn import_lookup_iter() {
+    let ilt: [u8; 12] = [0xA0, 0x20, 0, 0, 0xF4, 0x01, 0, 0x80, 0, 0, 0, 0];
+    let lookups: Vec<ImportLookup> = ImportLookupIter::new(&ilt, false)
+        .map(|l| l.unwrap())
+        .collect();
+
+    assert_eq!(
+        lookups,
+        [ImportLookup::ByName(0x20A0), ImportLookup::ByOrdinal(0x1F4)]
+    );
+
+    let ilt: [u8; 24] = [
+        0x78, 0x31, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    let lookups: Vec<ImportLookup> = ImportLookupIter::new(&ilt, true)
+        .map(|l| l.unwrap())
+        .collect();
+
+    assert_eq!(
+        lookups,
+        [ImportLookup::ByName(0x3178), ImportLookup::ByOrdinal(0x10)]
+    );
+}
+
+#[test]
+fn imports_pe32_plus() {
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let imports = pe_hdr.imports().unwrap();
+
+    assert_eq!(imports.len(), 2);
+    assert_eq!(imports[0].dll_name, "KERNEL32.dll");
+    assert_eq!(
+        imports[0].functions,
+        [ImportFunction {
+            entry: ImportEntry::ByName {
+                hint: 358,
+                name: "ExitProcess".to_string()
+            },
+            iat_rva: 0x3000
+        }]
+    );
+
+    assert_eq!(imports[1].dll_name, "USER32.dll");
+    assert_eq_hex!(imports[1].descriptor.first_thunk, 0x3010);
+    assert_eq!(
+        imports[1].functions,
+        [ImportFunction {
+            entry: ImportEntry::ByName {
+                hint: 645,
+                name: "MessageBoxA".to_string()
+            },
+            iat_rva: 0x3010
+        }]
+    );
+}
+
+#[test]
+fn imports_pe32() {
+    let mut buf = read_test_pe32();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let imports = pe_hdr.imports().unwrap();
+
+    assert_eq!(imports.len(), 2);
+    assert_eq!(imports[0].dll_name, "KERNEL32.dll");
+    assert_eq!(
+        imports[0].functions,
+        [
+            ImportFunction {
+                entry: ImportEntry::ByName {
+                    hint: 0x120,
+                    name: "ExitProcess".to_string()
+                },
+                iat_rva: 0x2010
+            },
+            ImportFunction {
+                entry: ImportEntry::ByName {
+                    hint: 0x200,
+                    name: "GetTickCount".to_string()
+                },
+                iat_rva: 0x2014
+            }
+        ]
+    );
+
+    assert_eq!(imports[1].dll_name, "USER32.dll");
+    assert_eq!(
+        imports[1].functions,
+        [ImportFunction {
+            entry: ImportEntry::ByOrdinal(0x1F4),
+            iat_rva: 0x201C
+        }]
+    );
+}
+
+#[test]
+fn imports_missing_ilt() {
+    // Clear the ILT pointer of the first descriptor, the IAT is used instead
+    let mut buf = read_test_pe32();
+    buf[0x440..0x444].copy_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let imports = pe_hdr.imports().unwrap();
+
+    assert_eq!(imports.len(), 2);
+    assert_eq!(imports[0].functions.len(), 2);
+    assert_eq!(
+        imports[0].functions[1].entry,
+        ImportEntry::ByName {
+            hint: 0x200,
+            name: "GetTickCount".to_string()
+        }
+    );
+    assert_eq!(imports[1].functions[0].entry, ImportEntry::ByOrdinal(0x1F4));
+}
+
+#[test]
+fn imports_iat_overflow() {
+    // The second IAT slot of the first descriptor would be past u32::MAX
+    let mut buf = read_test_pe32();
+    buf[0x450..0x454].copy_from_slice(&[0xFC, 0xFF, 0xFF, 0xFF]);
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(
+        pe_hdr.imports(),
+        Err(PeError::RvaNotMapped { rva: 0xFFFF_FFFC })
+    );
+}
+
+#[test]
+fn add_import_pe32_plus() {
+    let buf = read_test_pe();
+    let functions = [
+        ImportEntry::ByName {
+            hint: 0,
+            name: "InstrumentInit".to_string(),
+        },
+        ImportEntry::ByOrdinal(7),
+    ];
+    let mut out = add_import(&buf, "instr.dll", &functions).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    let imports = pe_hdr.imports().unwrap();
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 6);
+    assert_eq!(*pe_hdr.sec_hdrs[5].name.as_ref(), b".idata2\0");
+    assert_eq_hex!(pe_hdr.sec_hdrs[5].virt_addr.val(), 0x6000);
+    assert_eq_hex!(pe_hdr.sec_hdrs[5].ptr_to_raw_data.val(), 0xE00);
+    assert_eq_hex!(pe_hdr.nt_hdr.opt_hdr.size_of_image.val(), 0x7000);
+
+    let data_dirs = &pe_hdr.nt_hdr.opt_hdr.data_dirs;
+    assert_eq!(
+        data_dirs.rva_and_size(DataDirType::Import),
+        Some((0x6000, 0x50))
+    );
+    assert_eq!(
+        data_dirs.rva_and_size(DataDirType::Iat),
+        Some((0x3000, 0x20))
+    );
+
+    assert_eq!(imports.len(), 3);
+    assert_eq!(imports[0].dll_name, "KERNEL32.dll");
+    assert_eq_hex!(imports[0].functions[0].iat_rva, 0x3000);
+    assert_eq!(imports[1].dll_name, "USER32.dll");
+    assert_eq_hex!(imports[1].functions[0].iat_rva, 0x3010);
+
+    assert_eq!(imports[2].dll_name, "instr.dll");
+    assert_eq!(imports[2].functions.len(), 2);
+    assert_eq!(imports[2].functions[0].entry, functions[0]);
+    assert_eq_hex!(imports[2].functions[0].iat_rva, 0x6068);
+    assert_eq!(imports[2].functions[1].entry, functions[1]);
+    assert_eq_hex!(imports[2].functions[1].iat_rva, 0x6070);
+}
+
+#[test]
+fn add_import_no_iat_dir() {
+    let mut buf = read_test_pe();
+
+    {
+        let mut pe_hdr = PeHeader::new(&mut buf);
+        let iat_dir = pe_hdr.nt_hdr.opt_hdr.data_dirs.iat.as_mut().unwrap();
+        iat_dir.virt_addr.set(0);
+        iat_dir.size.set(0);
+    }
+
+    let mut out = add_import(&buf, "instr.dll", &[ImportEntry::ByOrdinal(7)]).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+
+    assert_eq!(
+        pe_hdr
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Iat),
+        Some((0x6060, 0x10))
+    );
+    assert_eq_hex!(pe_hdr.imports().unwrap()[2].functions[0].iat_rva, 0x6060);
+}
+
+#[test]
+fn add_import_pe32() {
+    // The section table fills the headers, so they are grown and the sections moved down
+    let buf = read_test_pe32();
+    let functions = [ImportEntry::ByName {
+        hint: 0x10,
+        name: "Hook".to_string(),
+    }];
+    let mut out = add_import(&buf, "hook.dll", &functions).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    let imports = pe_hdr.imports().unwrap();
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 4);
+    assert_eq_hex!(pe_hdr.nt_hdr.opt_hdr.size_of_hdrs.val(), 0x400);
+    assert_eq_hex!(pe_hdr.sec_hdrs[0].ptr_to_raw_data.val(), 0x400);
+    assert_eq_hex!(pe_hdr.sec_hdrs[3].virt_addr.val(), 0x4000);
+    assert_eq_hex!(pe_hdr.sec_hdrs[3].ptr_to_raw_data.val(), 0xA00);
+    assert_eq_hex!(pe_hdr.nt_hdr.opt_hdr.size_of_image.val(), 0x5000);
+
+    assert_eq!(imports.len(), 3);
+    assert_eq!(imports[0].dll_name, "KERNEL32.dll");
+    assert_eq!(imports[0].functions.len(), 2);
+    assert_eq!(imports[1].functions[0].entry, ImportEntry::ByOrdinal(0x1F4));
+    assert_eq!(imports[2].dll_name, "hook.dll");
+    assert_eq!(imports[2].functions[0].entry, functions[0]);
+    assert_eq_hex!(imports[2].functions[0].iat_rva, 0x4058);
+}
+
+#[test]
+fn add_import_address_space_full() {
+    // .reloc already reaches the end of the address space
+    let mut buf = read_test_pe();
+    PeHeader::new(&mut buf).sec_hdrs[4]
+        .virt_addr
+        .set(0xFFFF_FF00);
+
+    assert_eq!(
+        PeHeader::new(&mut buf.clone()).next_sec_virt_addr(),
+        Err(PeError::AddressOverflow)
+    );
+    assert_eq!(
+        add_import(&buf, "hook.dll", &[ImportEntry::ByOrdinal(1)]).err(),
+        Some(PeError::AddressOverflow)
+    );
+}
@@ -8,10 +8,17 @@ extern crate std;
 extern crate alloc;
 
 pub mod dos_hdr;
+pub mod error;
+pub mod exceptions;
+pub mod exports;
 pub mod imports;
+pub mod manifest;
 pub mod nt_hdr;
 pub mod pe;
 pub mod relocs;
+pub mod resources;
 pub mod sec_hdr;
+pub mod sections;
+pub mod version;
 #[macro_use]
 pub mod util;
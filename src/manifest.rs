@@ -0,0 +1,364 @@
+use crate::{
+    error::PeError,
+    pe::PeHeader,
+    resources::{replace_resource_data, ResourceBuilder, ResourceId, RT_MANIFEST},
+};
+use alloc::prelude::v1::*;
+#[allow(unused_imports)]
+use assert_hex::assert_eq_hex;
+
+// Manifest resource IDs the loader looks at, for executables and for DLLs
+pub const CREATEPROCESS_MANIFEST_RESOURCE_ID: u16 = 1;
+pub const ISOLATIONAWARE_MANIFEST_RESOURCE_ID: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecutionLevel {
+    AsInvoker,
+    HighestAvailable,
+    RequireAdministrator,
+}
+
+impl ExecutionLevel {
+    pub fn new(level: &str) -> Option<Self> {
+        match level {
+            "asInvoker" => Some(Self::AsInvoker),
+            "highestAvailable" => Some(Self::HighestAvailable),
+            "requireAdministrator" => Some(Self::RequireAdministrator),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AsInvoker => "asInvoker",
+            Self::HighestAvailable => "highestAvailable",
+            Self::RequireAdministrator => "requireAdministrator",
+        }
+    }
+}
+
+// An element's local name, everything between it and the closing '>' and its text up to the next
+// tag. Self closing elements have no text
+struct Tag<'x> {
+    name: &'x str,
+    attrs: &'x str,
+    text: Option<&'x str>,
+}
+
+// Just enough of XML for manifests: no entities, CDATA or DTDs. Namespace prefixes are dropped
+// so asmv3:requestedExecutionLevel matches requestedExecutionLevel
+fn tags(xml: &str) -> Vec<Tag<'_>> {
+    let mut tags = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+
+        if rest.starts_with("!--") {
+            match rest.find("-->") {
+                Some(end) => {
+                    rest = &rest[end + 3..];
+                    continue;
+                }
+                None => break,
+            }
+        }
+
+        // Declarations, processing instructions and closing tags
+        if rest.starts_with('?') || rest.starts_with('!') || rest.starts_with('/') {
+            continue;
+        }
+
+        // A '>' inside a quoted attribute value doesn't end the tag
+        let mut quote = None;
+        let mut end = None;
+
+        for (i, c) in rest.char_indices() {
+            match (quote, c) {
+                (None, '"') | (None, '\'') => quote = Some(c),
+                (Some(q), c) if c == q => quote = None,
+                (None, '>') => {
+                    end = Some(i);
+                    break;
+                }
+                _ => (),
+            }
+        }
+
+        let end = match end {
+            Some(end) => end,
+            None => break,
+        };
+
+        let body = &rest[..end];
+        let self_closing = body.ends_with('/');
+        let body = body.trim_end_matches('/');
+        let name_end = body.find(char::is_whitespace).unwrap_or(body.len());
+
+        rest = &rest[end + 1..];
+
+        let text = if self_closing {
+            None
+        } else {
+            Some(&rest[..rest.find('<').unwrap_or(rest.len())])
+        };
+
+        tags.push(Tag {
+            name: local_name(&body[..name_end]),
+            attrs: &body[name_end..],
+            text,
+        });
+    }
+
+    tags
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+fn attr<'x>(attrs: &'x str, name: &str) -> Option<&'x str> {
+    let mut rest = attrs;
+
+    loop {
+        let eq = rest.find('=')?;
+        let key = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let quote = value.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        let close = value[1..].find(quote)? + 1;
+
+        if local_name(key) == name {
+            return Some(&value[1..close]);
+        }
+
+        rest = &value[close + 1..];
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub id: u16,
+    pub lang: u16,
+    pub xml: Vec<u8>,
+}
+
+impl Manifest {
+    fn with_tags<T, F: FnOnce(Vec<Tag<'_>>) -> T>(&self, f: F) -> T {
+        let xml = String::from_utf8_lossy(&self.xml);
+        // A UTF-8 byte order mark is allowed before the declaration
+        f(tags(xml.trim_start_matches('\u{FEFF}')))
+    }
+
+    pub fn requested_execution_level(&self) -> Option<ExecutionLevel> {
+        self.with_tags(|tags| {
+            tags.iter()
+                .find(|t| t.name == "requestedExecutionLevel")
+                .and_then(|t| attr(t.attrs, "level"))
+                .and_then(ExecutionLevel::new)
+        })
+    }
+
+    pub fn ui_access(&self) -> Option<bool> {
+        self.with_tags(|tags| {
+            match tags
+                .iter()
+                .find(|t| t.name == "requestedExecutionLevel")
+                .and_then(|t| attr(t.attrs, "uiAccess"))
+            {
+                Some("true") => Some(true),
+                Some("false") => Some(false),
+                _ => None,
+            }
+        })
+    }
+
+    // The dpiAware setting as written, e.g. "true" or "true/pm"
+    pub fn dpi_aware(&self) -> Option<String> {
+        self.with_tags(|tags| {
+            tags.iter()
+                .find(|t| t.name == "dpiAware")
+                .and_then(|t| t.text)
+                .map(|text| text.trim().to_string())
+        })
+    }
+
+    // Ids of the supportedOS elements, GUIDs in braces
+    pub fn supported_os(&self) -> Vec<String> {
+        self.with_tags(|tags| {
+            tags.iter()
+                .filter(|t| t.name == "supportedOS")
+                .filter_map(|t| attr(t.attrs, "Id"))
+                .map(|id| id.to_string())
+                .collect()
+        })
+    }
+
+    // Replaces the manifest resource with the same ID and language, adding it if there isn't one
+    pub fn write(&self, buf: &[u8]) -> Result<Vec<u8>, PeError> {
+        let mut out = buf.to_vec();
+        let pe_hdr = PeHeader::try_new(&mut out)?;
+        let type_id = ResourceId::Id(RT_MANIFEST);
+        let name = ResourceId::Id(self.id);
+
+        let existing = pe_hdr
+            .resources()?
+            .into_iter()
+            .find(|r| r.type_id == type_id && r.name == name && r.lang == self.lang);
+
+        if let Some(r) = existing {
+            return replace_resource_data(buf, &r, &self.xml);
+        }
+
+        let mut builder = match pe_hdr.resource_directory()? {
+            Some(_) => ResourceBuilder::from_pe(&pe_hdr)?,
+            None => ResourceBuilder::new(),
+        };
+
+        builder.insert(type_id, name, self.lang, self.xml.clone());
+        builder.write(buf)
+    }
+}
+
+impl<'a> PeHeader<'a> {
+    // The RT_MANIFEST resource the loader would use, preferring ID 1 over ID 2
+    pub fn manifest(&self) -> Result<Option<Manifest>, PeError> {
+        let manifests: Vec<_> = self
+            .resources()?
+            .into_iter()
+            .filter(|r| r.type_id == ResourceId::Id(RT_MANIFEST))
+            .collect();
+
+        for id in [
+            CREATEPROCESS_MANIFEST_RESOURCE_ID,
+            ISOLATIONAWARE_MANIFEST_RESOURCE_ID,
+        ]
+        .iter()
+        {
+            if let Some(r) = manifests.iter().find(|r| r.name == ResourceId::Id(*id)) {
+                return Ok(Some(Manifest {
+                    id: *id,
+                    lang: r.lang,
+                    xml: r.data.clone(),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+//Tests
+#[cfg(feature = "std_unit_tests")]
+#[cfg(test)]
+use crate::pe::{read_test_pe, read_test_pe64};
+
+#[test]
+fn manifest() {
+    let mut buf = read_test_pe64();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let manifest = pe_hdr.manifest().unwrap().unwrap();
+
+    assert_eq!(manifest.id, ISOLATIONAWARE_MANIFEST_RESOURCE_ID);
+    assert_eq_hex!(manifest.lang, 0x409);
+    assert_eq!(manifest.xml[..5], *b"<?xml");
+    assert_eq!(
+        manifest.requested_execution_level(),
+        Some(ExecutionLevel::RequireAdministrator)
+    );
+    assert_eq!(manifest.ui_access(), Some(false));
+    assert_eq!(manifest.dpi_aware().as_deref(), Some("true/pm"));
+    assert_eq!(
+        manifest.supported_os(),
+        vec![
+            "{8e0f7a12-bfb3-4fe8-b9a5-48fd50a15a9a}",
+            "{1f676c76-80e1-4239-95bb-83d0f6d0da78}"
+        ]
+    );
+
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::new(&mut buf);
+    assert_eq!(pe_hdr.manifest(), Ok(None));
+}
+
+#[test]
+fn manifest_scan() {
+    let manifest = Manifest {
+        id: 1,
+        lang: 0,
+        xml: "\u{FEFF}<?xml version='1.0'?>\n\
+              <!-- <requestedExecutionLevel level=\"highestAvailable\"/> -->\n\
+              <asmv1:assembly xmlns:asmv1='urn:schemas-microsoft-com:asm.v1'>\n\
+              <asmv3:requestedExecutionLevel uiAccess = 'true' note='a>b' level='asInvoker'/>\n\
+              <dpiAware>  true </dpiAware>\n\
+              </asmv1:assembly>"
+            .as_bytes()
+            .to_vec(),
+    };
+
+    assert_eq!(
+        manifest.requested_execution_level(),
+        Some(ExecutionLevel::AsInvoker)
+    );
+    assert_eq!(manifest.ui_access(), Some(true));
+    assert_eq!(manifest.dpi_aware().as_deref(), Some("true"));
+    assert!(manifest.supported_os().is_empty());
+
+    let manifest = Manifest {
+        xml: b"<assembly><requestedExecutionLevel level=\"root\"/></assembly>".to_vec(),
+        ..manifest
+    };
+    assert_eq!(manifest.requested_execution_level(), None);
+    assert_eq!(manifest.ui_access(), None);
+    assert_eq!(manifest.dpi_aware(), None);
+}
+
+#[test]
+fn manifest_write() {
+    let buf = read_test_pe64();
+    let mut manifest = PeHeader::new(&mut buf.clone()).manifest().unwrap().unwrap();
+    let xml = String::from_utf8(manifest.xml.clone()).unwrap();
+
+    // Same length or shorter stays in place
+    manifest.xml = xml
+        .replace("requireAdministrator", "asInvoker")
+        .into_bytes();
+    let mut out = manifest.write(&buf).unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 4);
+    assert_eq!(pe_hdr.manifest().unwrap().unwrap(), manifest);
+    assert_eq!(
+        pe_hdr
+            .manifest()
+            .unwrap()
+            .unwrap()
+            .requested_execution_level(),
+        Some(ExecutionLevel::AsInvoker)
+    );
+
+    // Longer is moved
+    manifest.xml = xml.replace("true/pm", "true/pm, permonitorv2").into_bytes();
+    let mut out = manifest.write(&buf).unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+    let written = pe_hdr.manifest().unwrap().unwrap();
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 5);
+    assert_eq!(
+        written.dpi_aware().as_deref(),
+        Some("true/pm, permonitorv2")
+    );
+    assert_eq!(pe_hdr.resources().unwrap().len(), 4);
+
+    // A manifest for a binary without any resources
+    let buf = read_test_pe();
+    let manifest = Manifest {
+        id: CREATEPROCESS_MANIFEST_RESOURCE_ID,
+        lang: 0x409,
+        xml: b"<assembly><requestedExecutionLevel level=\"highestAvailable\"/></assembly>".to_vec(),
+    };
+    let mut out = manifest.write(&buf).unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+
+    assert_eq!(pe_hdr.manifest().unwrap().unwrap(), manifest);
+    assert_eq!(pe_hdr.resources().unwrap().len(), 1);
+}
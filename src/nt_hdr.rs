@@ -1,13 +1,57 @@
 use zordon::types::*;
 use zordon::MutView;
 
-#[derive(MutView)]
+pub const NT_SIG: u32 = 0x4550;
+pub const PE32_MAGIC: u16 = 0x10B;
+pub const PE32_PLUS_MAGIC: u16 = 0x20B;
+// Signature and file header, the optional header magic follows directly after
+pub const NT_FIXED_HDR_SIZE: usize = 0x18;
+// Optional header sizes up to (not including) the data directories
+pub const OPT_HDR32_SIZE: usize = 0x60;
+pub const OPT_HDR64_SIZE: usize = 0x70;
+pub const DATA_DIR_SIZE: usize = 0x08;
+pub const MAX_DATA_DIRS: usize = 0x10;
+
+pub const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
+pub const IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE: u16 = 0x0040;
+
+pub const IMAGE_FILE_MACHINE_I386: u16 = 0x14C;
+pub const IMAGE_FILE_MACHINE_ARM: u16 = 0x1C0;
+pub const IMAGE_FILE_MACHINE_THUMB: u16 = 0x1C2;
+pub const IMAGE_FILE_MACHINE_ARMNT: u16 = 0x1C4;
+pub const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+pub const IMAGE_FILE_MACHINE_ARM64: u16 = 0xAA64;
+pub const IMAGE_FILE_MACHINE_RISCV32: u16 = 0x5032;
+pub const IMAGE_FILE_MACHINE_RISCV64: u16 = 0x5064;
+pub const IMAGE_FILE_MACHINE_RISCV128: u16 = 0x5128;
+pub const IMAGE_FILE_MACHINE_LOONGARCH32: u16 = 0x6232;
+pub const IMAGE_FILE_MACHINE_LOONGARCH64: u16 = 0x6264;
+
 pub struct NtHeader<'a> {
     pub sig: MulByteView<'a, u32, LitEnd>,
     pub file_hdr: FileHeader<'a>,
     pub opt_hdr: OptHeader<'a>,
 }
 
+impl<'a> NtHeader<'a> {
+    // The optional header view is bounded by opt_hdr_size, so the leftover starts at the section table
+    pub fn mut_view(buf: &'a mut [u8]) -> (Self, &'a mut [u8]) {
+        let (sig, buf) = MulByteView::mut_view(buf);
+        let (file_hdr, buf) = FileHeader::mut_view(buf);
+        let (opt_hdr_buf, buf) = buf.split_at_mut(file_hdr.opt_hdr_size.val() as usize);
+        let (opt_hdr, _) = OptHeader::mut_view(opt_hdr_buf);
+
+        (
+            Self {
+                sig,
+                file_hdr,
+                opt_hdr,
+            },
+            buf,
+        )
+    }
+}
+
 #[derive(MutView)]
 pub struct FileHeader<'a> {
     pub machine: MulByteView<'a, u16, LitEnd>,
@@ -19,7 +63,39 @@ pub struct FileHeader<'a> {
     pub file_characteristics: MulByteView<'a, u16, LitEnd>, // TODO: Think about making this into bitfields struct
 }
 
-#[derive(MutView)]
+// Fields which are a u32 in PE32 images and a u64 in PE32+ images
+#[derive(Debug, PartialEq)]
+pub enum NativeView<'a> {
+    Pe32(MulByteView<'a, u32, LitEnd>),
+    Pe64(MulByteView<'a, u64, LitEnd>),
+}
+
+impl<'a> NativeView<'a> {
+    pub fn mut_view(buf: &'a mut [u8], pe32_plus: bool) -> (Self, &'a mut [u8]) {
+        if pe32_plus {
+            let (v, buf) = MulByteView::mut_view(buf);
+            (Self::Pe64(v), buf)
+        } else {
+            let (v, buf) = MulByteView::mut_view(buf);
+            (Self::Pe32(v), buf)
+        }
+    }
+
+    pub fn val(&self) -> u64 {
+        match self {
+            Self::Pe32(v) => v.val() as u64,
+            Self::Pe64(v) => v.val(),
+        }
+    }
+
+    pub fn set(&mut self, val: u64) {
+        match self {
+            Self::Pe32(v) => v.set(val as u32),
+            Self::Pe64(v) => v.set(val),
+        }
+    }
+}
+
 pub struct OptHeader<'a> {
     pub magic: MulByteView<'a, u16, LitEnd>,
     pub major_linker_ver: ByteView<'a, u8>,
@@ -29,7 +105,8 @@ pub struct OptHeader<'a> {
     pub size_of_uninit_data: MulByteView<'a, u32, LitEnd>,
     pub addr_of_entrypoint: MulByteView<'a, u32, LitEnd>,
     pub base_of_code: MulByteView<'a, u32, LitEnd>,
-    pub image_base: MulByteView<'a, u64, LitEnd>,
+    pub base_of_data: Option<MulByteView<'a, u32, LitEnd>>, // PE32 only
+    pub image_base: NativeView<'a>,
     pub sec_alignment: MulByteView<'a, u32, LitEnd>,
     pub file_alignment: MulByteView<'a, u32, LitEnd>,
     pub major_os_ver: MulByteView<'a, u16, LitEnd>,
@@ -44,16 +121,116 @@ pub struct OptHeader<'a> {
     pub checksum: MulByteView<'a, u32, LitEnd>,
     pub subsystem: MulByteView<'a, u16, LitEnd>,
     pub dll_characteristics: MulByteView<'a, u16, LitEnd>, // TODO: another one that can be made into bitfields struct
-    pub size_of_stack_reservee: MulByteView<'a, u64, LitEnd>,
-    pub size_of_stack_commit: MulByteView<'a, u64, LitEnd>,
-    pub size_of_heap_reserve: MulByteView<'a, u64, LitEnd>,
-    pub size_of_heap_commit: MulByteView<'a, u64, LitEnd>,
+    pub size_of_stack_reservee: NativeView<'a>,
+    pub size_of_stack_commit: NativeView<'a>,
+    pub size_of_heap_reserve: NativeView<'a>,
+    pub size_of_heap_commit: NativeView<'a>,
     pub loader_flags: MulByteView<'a, u32, LitEnd>,
     pub num_of_rva_and_sizes: MulByteView<'a, u32, LitEnd>,
     pub data_dirs: DataDirectories<'a>,
 }
 
-#[derive(MutView)]
+impl<'a> OptHeader<'a> {
+    // Size of the optional header up to the data directories, None for an unknown magic
+    pub fn fixed_size(magic: u16) -> Option<usize> {
+        match magic {
+            PE32_MAGIC => Some(OPT_HDR32_SIZE),
+            PE32_PLUS_MAGIC => Some(OPT_HDR64_SIZE),
+            _ => None,
+        }
+    }
+
+    // Dispatches on the magic, anything other than PE32_MAGIC is viewed as PE32+. Only the data
+    // directories covered by both num_of_rva_and_sizes and buf are viewed, the rest are None
+    pub fn mut_view(buf: &'a mut [u8]) -> (Self, &'a mut [u8]) {
+        let (magic, buf): (MulByteView<u16, LitEnd>, _) = MulByteView::mut_view(buf);
+        let pe32_plus = magic.val() != PE32_MAGIC;
+
+        let (major_linker_ver, buf) = ByteView::mut_view(buf);
+        let (minor_linker_ver, buf) = ByteView::mut_view(buf);
+        let (size_of_code, buf) = MulByteView::mut_view(buf);
+        let (size_of_init_data, buf) = MulByteView::mut_view(buf);
+        let (size_of_uninit_data, buf) = MulByteView::mut_view(buf);
+        let (addr_of_entrypoint, buf) = MulByteView::mut_view(buf);
+        let (base_of_code, buf) = MulByteView::mut_view(buf);
+
+        let (base_of_data, buf) = if pe32_plus {
+            (None, buf)
+        } else {
+            let (v, buf) = MulByteView::mut_view(buf);
+            (Some(v), buf)
+        };
+
+        let (image_base, buf) = NativeView::mut_view(buf, pe32_plus);
+        let (sec_alignment, buf) = MulByteView::mut_view(buf);
+        let (file_alignment, buf) = MulByteView::mut_view(buf);
+        let (major_os_ver, buf) = MulByteView::mut_view(buf);
+        let (minor_os_ver, buf) = MulByteView::mut_view(buf);
+        let (major_image_ver, buf) = MulByteView::mut_view(buf);
+        let (minor_image_ver, buf) = MulByteView::mut_view(buf);
+        let (major_subsystem_ver, buf) = MulByteView::mut_view(buf);
+        let (minor_subsystem_ver, buf) = MulByteView::mut_view(buf);
+        let (win32_ver_val, buf) = MulByteView::mut_view(buf);
+        let (size_of_image, buf) = MulByteView::mut_view(buf);
+        let (size_of_hdrs, buf) = MulByteView::mut_view(buf);
+        let (checksum, buf) = MulByteView::mut_view(buf);
+        let (subsystem, buf) = MulByteView::mut_view(buf);
+        let (dll_characteristics, buf) = MulByteView::mut_view(buf);
+        let (size_of_stack_reservee, buf) = NativeView::mut_view(buf, pe32_plus);
+        let (size_of_stack_commit, buf) = NativeView::mut_view(buf, pe32_plus);
+        let (size_of_heap_reserve, buf) = NativeView::mut_view(buf, pe32_plus);
+        let (size_of_heap_commit, buf) = NativeView::mut_view(buf, pe32_plus);
+        let (loader_flags, buf) = MulByteView::mut_view(buf);
+        let (num_of_rva_and_sizes, buf): (MulByteView<u32, LitEnd>, _) = MulByteView::mut_view(buf);
+
+        let num_of_data_dirs = (num_of_rva_and_sizes.val() as usize)
+            .min(MAX_DATA_DIRS)
+            .min(buf.len() / DATA_DIR_SIZE);
+        let (data_dirs, buf) = DataDirectories::mut_view(buf, num_of_data_dirs);
+
+        (
+            Self {
+                magic,
+                major_linker_ver,
+                minor_linker_ver,
+                size_of_code,
+                size_of_init_data,
+                size_of_uninit_data,
+                addr_of_entrypoint,
+                base_of_code,
+                base_of_data,
+                image_base,
+                sec_alignment,
+                file_alignment,
+                major_os_ver,
+                minor_os_ver,
+                major_image_ver,
+                minor_image_ver,
+                major_subsystem_ver,
+                minor_subsystem_ver,
+                win32_ver_val,
+                size_of_image,
+                size_of_hdrs,
+                checksum,
+                subsystem,
+                dll_characteristics,
+                size_of_stack_reservee,
+                size_of_stack_commit,
+                size_of_heap_reserve,
+                size_of_heap_commit,
+                loader_flags,
+                num_of_rva_and_sizes,
+                data_dirs,
+            },
+            buf,
+        )
+    }
+
+    pub fn is_pe32_plus(&self) -> bool {
+        self.magic.val() != PE32_MAGIC
+    }
+}
+
 pub struct DataDirectories<'a> {
     pub export: Option<DataDirectory<'a>>,
     pub import: Option<DataDirectory<'a>>,
@@ -72,13 +249,143 @@ pub struct DataDirectories<'a> {
     pub com_descriptor: Option<DataDirectory<'a>>,
     pub reserved: Option<DataDirectory<'a>>,
 }
+
+impl<'a> DataDirectories<'a> {
+    pub fn mut_view(buf: &'a mut [u8], count: usize) -> (Self, &'a mut [u8]) {
+        let mut buf = buf;
+        let mut count = count;
+
+        // Fields are evaluated in order, so each call views the next directory in buf
+        (
+            Self {
+                export: Self::next_dir(&mut buf, &mut count),
+                import: Self::next_dir(&mut buf, &mut count),
+                resource: Self::next_dir(&mut buf, &mut count),
+                exception: Self::next_dir(&mut buf, &mut count),
+                security: Self::next_dir(&mut buf, &mut count),
+                base_reloc: Self::next_dir(&mut buf, &mut count),
+                debug: Self::next_dir(&mut buf, &mut count),
+                architecture: Self::next_dir(&mut buf, &mut count),
+                global_ptr: Self::next_dir(&mut buf, &mut count),
+                tls: Self::next_dir(&mut buf, &mut count),
+                load_config: Self::next_dir(&mut buf, &mut count),
+                bound_import: Self::next_dir(&mut buf, &mut count),
+                iat: Self::next_dir(&mut buf, &mut count),
+                delay_import: Self::next_dir(&mut buf, &mut count),
+                com_descriptor: Self::next_dir(&mut buf, &mut count),
+                reserved: Self::next_dir(&mut buf, &mut count),
+            },
+            buf,
+        )
+    }
+
+    pub fn get(&self, dir: DataDirType) -> Option<&DataDirectory<'a>> {
+        match dir {
+            DataDirType::Export => self.export.as_ref(),
+            DataDirType::Import => self.import.as_ref(),
+            DataDirType::Resource => self.resource.as_ref(),
+            DataDirType::Exception => self.exception.as_ref(),
+            DataDirType::Security => self.security.as_ref(),
+            DataDirType::Reloc => self.base_reloc.as_ref(),
+            DataDirType::Debug => self.debug.as_ref(),
+            DataDirType::Architecture => self.architecture.as_ref(),
+            DataDirType::GlobalPtr => self.global_ptr.as_ref(),
+            DataDirType::Tls => self.tls.as_ref(),
+            DataDirType::LoadConfig => self.load_config.as_ref(),
+            DataDirType::BoundImport => self.bound_import.as_ref(),
+            DataDirType::Iat => self.iat.as_ref(),
+            DataDirType::DelayImport => self.delay_import.as_ref(),
+            DataDirType::ComDescriptor => self.com_descriptor.as_ref(),
+            DataDirType::Reserved => self.reserved.as_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self, dir: DataDirType) -> Option<&mut DataDirectory<'a>> {
+        match dir {
+            DataDirType::Export => self.export.as_mut(),
+            DataDirType::Import => self.import.as_mut(),
+            DataDirType::Resource => self.resource.as_mut(),
+            DataDirType::Exception => self.exception.as_mut(),
+            DataDirType::Security => self.security.as_mut(),
+            DataDirType::Reloc => self.base_reloc.as_mut(),
+            DataDirType::Debug => self.debug.as_mut(),
+            DataDirType::Architecture => self.architecture.as_mut(),
+            DataDirType::GlobalPtr => self.global_ptr.as_mut(),
+            DataDirType::Tls => self.tls.as_mut(),
+            DataDirType::LoadConfig => self.load_config.as_mut(),
+            DataDirType::BoundImport => self.bound_import.as_mut(),
+            DataDirType::Iat => self.iat.as_mut(),
+            DataDirType::DelayImport => self.delay_import.as_mut(),
+            DataDirType::ComDescriptor => self.com_descriptor.as_mut(),
+            DataDirType::Reserved => self.reserved.as_mut(),
+        }
+    }
+
+    // (virt_addr, size) of a directory that is both present and non empty
+    pub fn rva_and_size(&self, dir: DataDirType) -> Option<(u32, u32)> {
+        match self.get(dir) {
+            Some(d) if d.virt_addr.val() != 0 => Some((d.virt_addr.val(), d.size.val())),
+            _ => None,
+        }
+    }
+
+    fn next_dir(buf: &mut &'a mut [u8], count: &mut usize) -> Option<DataDirectory<'a>> {
+        if *count == 0 {
+            return None;
+        }
+
+        let (dir, leftover) = DataDirectory::mut_view(core::mem::take(buf));
+        *buf = leftover;
+        *count -= 1;
+
+        Some(dir)
+    }
+}
+
 #[derive(MutView)]
 pub struct DataDirectory<'a> {
     pub virt_addr: MulByteView<'a, u32, LitEnd>,
     pub size: MulByteView<'a, u32, LitEnd>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DataDirType {
+    Export,
     Import,
-    Reloc
+    Resource,
+    Exception,
+    Security,
+    Reloc,
+    Debug,
+    Architecture,
+    GlobalPtr,
+    Tls,
+    LoadConfig,
+    BoundImport,
+    Iat,
+    DelayImport,
+    ComDescriptor,
+    Reserved,
+}
+
+impl DataDirType {
+    // In data directory order
+    pub const ALL: [DataDirType; MAX_DATA_DIRS] = [
+        DataDirType::Export,
+        DataDirType::Import,
+        DataDirType::Resource,
+        DataDirType::Exception,
+        DataDirType::Security,
+        DataDirType::Reloc,
+        DataDirType::Debug,
+        DataDirType::Architecture,
+        DataDirType::GlobalPtr,
+        DataDirType::Tls,
+        DataDirType::LoadConfig,
+        DataDirType::BoundImport,
+        DataDirType::Iat,
+        DataDirType::DelayImport,
+        DataDirType::ComDescriptor,
+        DataDirType::Reserved,
+    ];
 }
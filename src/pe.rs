@@ -1,38 +1,110 @@
-#[allow(unused_attributes)]
-#[macro_use]
-#[allow(unused_imports)]
-use crate::fmt_err;
 use crate::{
-    dos_hdr::DosHeader, imports::ImportDescriptor, nt_hdr::*, relocs::Relocation,
-    sec_hdr::SectionHeader,
+    dos_hdr::{DosHeader, DOS_HDR_SIZE, DOS_SIG},
+    error::PeError,
+    nt_hdr::*,
+    sec_hdr::{SectionHeader, SEC_HDR_SIZE},
+    util::align_up,
 };
-use zordon::prelude::*;
 use alloc::prelude::v1::*;
-use alloc::format;
+use byteorder::{ByteOrder, LittleEndian};
+use core::cell::{Ref, RefMut};
+use core::ops::Range;
+use zordon::prelude::*;
 
 pub struct PeHeader<'a> {
     pub dos_hdr: DosHeader<'a>,
     pub nt_hdr: NtHeader<'a>,
-    pub sec_hdrs: Vec<SectionHeader<'a>>, // These could/should be Option
-    pub secs: Vec<VarArrayView<'a, u8>>,
+    pub sec_hdrs: Vec<SectionHeader<'a>>,
+    // Everything after the section table up to the end of the buffer
+    pub body: VarArrayView<'a, u8>,
+    pub body_offset: usize,
+    // File offset range of each section's raw data, paired with sec_hdrs. None for sections
+    // without raw data, ranges are clamped to the end of the buffer and may overlap
+    pub sec_ranges: Vec<Option<Range<usize>>>,
 }
 
 impl<'a> PeHeader<'a> {
     pub fn new(rwbuf: &'a mut [u8]) -> Self {
+        Self::try_new(rwbuf).unwrap()
+    }
+
+    pub fn try_new(rwbuf: &'a mut [u8]) -> Result<Self, PeError> {
         let rwbuf_len = rwbuf.len();
 
+        if rwbuf_len < DOS_HDR_SIZE {
+            return Err(PeError::Truncated {
+                offset: 0,
+                needed: DOS_HDR_SIZE,
+            });
+        }
+
         let (dos_hdr, leftover) = DosHeader::mut_view(rwbuf);
 
-        let (_, leftover) =
-            leftover.split_at_mut(dos_hdr.addr_of_new_exe_hdr.val() as usize - 0x40);
+        if dos_hdr.mz_sig.val() != DOS_SIG {
+            return Err(PeError::BadDosSignature {
+                found: dos_hdr.mz_sig.val(),
+            });
+        }
+
+        let nt_hdr_offset = dos_hdr.addr_of_new_exe_hdr.val() as usize;
+
+        if nt_hdr_offset < DOS_HDR_SIZE {
+            return Err(PeError::BadNewExeHdrOffset {
+                offset: dos_hdr.addr_of_new_exe_hdr.val(),
+            });
+        }
+
+        if rwbuf_len.saturating_sub(nt_hdr_offset) < NT_FIXED_HDR_SIZE + 2 {
+            return Err(PeError::Truncated {
+                offset: nt_hdr_offset,
+                needed: NT_FIXED_HDR_SIZE + 2,
+            });
+        }
+
+        let (_, leftover) = leftover.split_at_mut(nt_hdr_offset - DOS_HDR_SIZE);
 
-        let (nt_hdr, mut leftover) = NtHeader::mut_view(leftover);
+        let sig = LittleEndian::read_u32(leftover);
+
+        if sig != NT_SIG {
+            return Err(PeError::BadNtSignature { found: sig });
+        }
+
+        let opt_hdr_size = LittleEndian::read_u16(&leftover[NT_FIXED_HDR_SIZE - 4..]) as usize;
+        let magic = LittleEndian::read_u16(&leftover[NT_FIXED_HDR_SIZE..]);
+
+        match OptHeader::fixed_size(magic) {
+            Some(fixed_size) if opt_hdr_size < fixed_size => {
+                return Err(PeError::BadOptHdrSize {
+                    size: opt_hdr_size as u16,
+                })
+            }
+            Some(_) => (),
+            None => return Err(PeError::BadOptHdrMagic { found: magic }),
+        };
+
+        let nt_hdr_size = NT_FIXED_HDR_SIZE + opt_hdr_size;
+
+        if leftover.len() < nt_hdr_size {
+            return Err(PeError::Truncated {
+                offset: nt_hdr_offset,
+                needed: nt_hdr_size,
+            });
+        }
+
+        let (nt_hdr, leftover) = NtHeader::mut_view(leftover);
 
         let num_of_secs = nt_hdr.file_hdr.num_of_secs.val();
         let mut sec_hdrs: Vec<SectionHeader> = Vec::with_capacity(num_of_secs as usize);
+        let mut leftover = leftover;
+
+        let sec_tbl_size = num_of_secs as usize * SEC_HDR_SIZE;
 
-        let (_, l) = leftover.split_at_mut(0x80);
-        leftover = l;
+        if sec_tbl_size > leftover.len() {
+            return Err(PeError::Truncated {
+                offset: rwbuf_len - leftover.len(),
+                needed: sec_tbl_size,
+            });
+        }
 
         for _ in 0..num_of_secs {
             let (slice, l) = SectionHeader::mut_view(leftover);
@@ -40,80 +112,269 @@ impl<'a> PeHeader<'a> {
             leftover = l;
         }
 
-        let mut secs: Vec<VarArrayView<u8>> = Vec::new();
-        sec_hdrs.sort_by_key(|s| s.ptr_to_raw_data.val());
+        let body_offset = rwbuf_len - leftover.len();
+        let mut sec_ranges: Vec<Option<Range<usize>>> = Vec::with_capacity(sec_hdrs.len());
 
-        for h in &sec_hdrs {
-            let rel_offset = rwbuf_len - leftover.len();
+        for (i, h) in sec_hdrs.iter().enumerate() {
+            let ptr_to_raw_data = h.ptr_to_raw_data.val() as usize;
+            let size_of_raw_data = h.size_of_raw_data.val() as usize;
 
-            let (_, left) = leftover.split_at_mut(h.ptr_to_raw_data.val() as usize - rel_offset);
-            let (sec, l) = VarArrayView::<u8>::mut_view(left, h.size_of_raw_data.val() as usize);
-            secs.push(sec);
+            // Uninitialized data, the loader zero fills the whole section
+            if ptr_to_raw_data == 0 || size_of_raw_data == 0 {
+                sec_ranges.push(None);
+                continue;
+            }
 
-            leftover = l;
+            if ptr_to_raw_data < body_offset {
+                return Err(PeError::SectionDataOverlap { index: i });
+            }
+
+            if ptr_to_raw_data > rwbuf_len {
+                return Err(PeError::SectionDataOutOfBounds { index: i });
+            }
+
+            let end = ptr_to_raw_data + size_of_raw_data.min(rwbuf_len - ptr_to_raw_data);
+            sec_ranges.push(Some(ptr_to_raw_data..end));
         }
 
-        Self {
+        let body_len = leftover.len();
+        let (body, _) = VarArrayView::<u8>::mut_view(leftover, body_len);
+
+        Ok(Self {
             dos_hdr,
             nt_hdr,
             sec_hdrs,
-            secs,
+            body,
+            body_offset,
+            sec_ranges,
+        })
+    }
+
+    // Raw data of section i, None when the section has none on disk
+    pub fn sec(&self, i: usize) -> Option<Ref<'_, [u8]>> {
+        let range = self.body_range(i)?;
+        Some(Ref::map(self.body.as_ref(), |b| &b[range]))
+    }
+
+    pub fn sec_mut(&self, i: usize) -> Option<RefMut<'_, [u8]>> {
+        let range = self.body_range(i)?;
+        Some(RefMut::map(self.body.as_mut_ref(), |b| &mut b[range]))
+    }
+
+    // Raw data from rva up to the end of the section's raw data
+    pub fn data_at_rva(&self, rva: u32) -> Result<Ref<'_, [u8]>, PeError> {
+        let range = self.rva_body_range(rva)?;
+        Ok(Ref::map(self.body.as_ref(), |b| &b[range]))
+    }
+
+    pub fn data_at_rva_mut(&self, rva: u32) -> Result<RefMut<'_, [u8]>, PeError> {
+        let range = self.rva_body_range(rva)?;
+        Ok(RefMut::map(self.body.as_mut_ref(), |b| &mut b[range]))
+    }
+
+    // len bytes of raw data at rva, all of which must be inside the section rva resides in
+    pub fn read_at_rva(&self, rva: u32, len: usize) -> Result<Ref<'_, [u8]>, PeError> {
+        let range = self.rva_body_range(rva)?;
+
+        if range.len() < len {
+            return Err(PeError::Truncated {
+                offset: self.body_offset + range.start,
+                needed: len,
+            });
+        }
+
+        Ok(Ref::map(self.body.as_ref(), |b| {
+            &b[range.start..range.start + len]
+        }))
+    }
+
+    pub fn read_u8_at_rva(&self, rva: u32) -> Result<u8, PeError> {
+        Ok(self.read_at_rva(rva, 1)?[0])
+    }
+
+    pub fn read_u16_at_rva(&self, rva: u32) -> Result<u16, PeError> {
+        Ok(LittleEndian::read_u16(&self.read_at_rva(rva, 2)?))
+    }
+
+    pub fn read_u32_at_rva(&self, rva: u32) -> Result<u32, PeError> {
+        Ok(LittleEndian::read_u32(&self.read_at_rva(rva, 4)?))
+    }
+
+    pub fn read_u64_at_rva(&self, rva: u32) -> Result<u64, PeError> {
+        Ok(LittleEndian::read_u64(&self.read_at_rva(rva, 8)?))
+    }
+
+    // Null terminated string at rva, the terminator has to be inside the section's raw data
+    pub fn read_c_string_at_rva(&self, rva: u32) -> Result<String, PeError> {
+        let range = self.rva_body_range(rva)?;
+        let data = &self.body.as_ref()[range.clone()];
+
+        match data.iter().position(|&b| b == 0) {
+            Some(end) => Ok(String::from_utf8_lossy(&data[..end]).into_owned()),
+            None => Err(PeError::Truncated {
+                offset: self.body_offset + range.end,
+                needed: 1,
+            }),
         }
     }
 
-    pub fn rva_to_file_offset(sec_hdrs: &Vec<SectionHeader>, rva: u32) -> Result<u32, String> {
-        for s in sec_hdrs.iter() {
-            if (s.virt_addr.val() <= rva) && ((s.virt_addr.val() + s.virt_size.val()) > rva) {
-                return Ok((rva - s.virt_addr.val()) + s.ptr_to_raw_data.val());
+    // len UTF-16 code units at rva, unpaired surrogates are replaced
+    pub fn read_utf16_at_rva(&self, rva: u32, len: usize) -> Result<String, PeError> {
+        let data = self.read_at_rva(rva, len * 2)?;
+        let units: Vec<u16> = data.chunks(2).map(LittleEndian::read_u16).collect();
+
+        Ok(String::from_utf16_lossy(&units))
+    }
+
+    // Body range from rva to the end of the raw data of the section it resides in
+    fn rva_body_range(&self, rva: u32) -> Result<Range<usize>, PeError> {
+        let i = self
+            .virt_addr_to_sec_index(rva)
+            .map_err(|_| PeError::RvaNotMapped { rva })?;
+        let offset = (rva - self.sec_hdrs[i].virt_addr.val()) as usize;
+
+        match self.body_range(i) {
+            Some(range) if offset < range.len() => Ok(range.start + offset..range.end),
+            _ => Err(PeError::RvaNotMapped { rva }),
+        }
+    }
+
+    fn body_range(&self, i: usize) -> Option<Range<usize>> {
+        let range = self.sec_ranges.get(i)?.as_ref()?;
+        Some(range.start - self.body_offset..range.end - self.body_offset)
+    }
+
+    // Section table indices ordered by ptr_to_raw_data, ties keep their table order
+    pub fn raw_data_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.sec_hdrs.len()).collect();
+        order.sort_by_key(|&i| self.sec_hdrs[i].ptr_to_raw_data.val());
+        order
+    }
+
+    pub fn is_pe32_plus(&self) -> bool {
+        self.nt_hdr.opt_hdr.is_pe32_plus()
+    }
+
+    pub fn image_base(&self) -> u64 {
+        self.nt_hdr.opt_hdr.image_base.val()
+    }
+
+    pub fn set_image_base(&mut self, image_base: u64) {
+        self.nt_hdr.opt_hdr.image_base.set(image_base)
+    }
+
+    pub fn va_to_rva(&self, va: u64) -> Result<u32, PeError> {
+        match va.checked_sub(self.image_base()) {
+            Some(rva) if rva <= u32::MAX as u64 && self.rva_region(rva as u32).is_ok() => {
+                Ok(rva as u32)
             }
+            _ => Err(PeError::VaNotMapped { va }),
+        }
+    }
+
+    pub fn rva_to_va(&self, rva: u32) -> Result<u64, PeError> {
+        self.rva_region(rva)?;
+        self.image_base()
+            .checked_add(rva as u64)
+            .ok_or(PeError::RvaNotMapped { rva })
+    }
+
+    pub fn rva_to_offset(&self, rva: u32) -> Result<usize, PeError> {
+        let s = match self.rva_region(rva)? {
+            Some(i) => &self.sec_hdrs[i],
+            None if rva < self.nt_hdr.opt_hdr.size_of_hdrs.val() => return Ok(rva as usize),
+            None => return Err(PeError::RvaHasNoRawData { rva }),
+        };
+        let sec_offset = rva - s.virt_addr.val();
+
+        // Past the raw data the loader zero fills up to the virtual size
+        if s.ptr_to_raw_data.val() == 0 || sec_offset >= s.size_of_raw_data.val() {
+            return Err(PeError::RvaHasNoRawData { rva });
+        }
+
+        Ok(s.ptr_to_raw_data.val() as usize + sec_offset as usize)
+    }
+
+    pub fn offset_to_rva(&self, offset: usize) -> Result<u32, PeError> {
+        if offset < self.nt_hdr.opt_hdr.size_of_hdrs.val() as usize {
+            return Ok(offset as u32);
         }
 
-        Err(fmt_err!("Could find section rva resides in"))
+        let s = &self.sec_hdrs[self.offset_to_section(offset)?];
+        Ok(s.virt_addr.val() + (offset - s.ptr_to_raw_data.val() as usize) as u32)
+    }
+
+    // Index of the first section whose raw data contains offset
+    pub fn offset_to_section(&self, offset: usize) -> Result<usize, PeError> {
+        self.sec_hdrs
+            .iter()
+            .position(|s| {
+                let start = s.ptr_to_raw_data.val() as usize;
+                start != 0 && start <= offset && offset - start < s.size_of_raw_data.val() as usize
+            })
+            .ok_or(PeError::OffsetNotMapped { offset })
     }
 
-    pub fn virt_addr_to_sec_index(&self, section_va: u32) -> Result<usize, String> {
+    // Section rva is mapped by, None when it falls in the headers mapped below the sections
+    fn rva_region(&self, rva: u32) -> Result<Option<usize>, PeError> {
+        if let Ok(i) = self.virt_addr_to_sec_index(rva) {
+            return Ok(Some(i));
+        }
+
+        let opt_hdr = &self.nt_hdr.opt_hdr;
+
+        if rva < align_up(opt_hdr.size_of_hdrs.val(), opt_hdr.sec_alignment.val())? {
+            Ok(None)
+        } else {
+            Err(PeError::RvaNotMapped { rva })
+        }
+    }
+
+    pub fn virt_addr_to_sec_index(&self, section_va: u32) -> Result<usize, PeError> {
         for (i, s) in self.sec_hdrs.iter().enumerate() {
-            if (s.virt_addr.val() <= section_va)
-                && ((s.virt_addr.val() + Self::sec_virt_size(s.size_of_raw_data.val()))
-                    > section_va)
+            let virt_addr = s.virt_addr.val();
+
+            if (virt_addr <= section_va)
+                && ((virt_addr as u64 + self.sec_virt_size(s)? as u64) > section_va as u64)
             {
                 return Ok(i);
             }
         }
 
-        Err(fmt_err!(
-            "Could not find section with va: {:#X}",
-            section_va
-        ))
+        Err(PeError::SectionNotFound { va: section_va })
     }
 
-    pub fn entry_sec_index(&self) -> Result<usize, String> {
+    pub fn entry_sec_index(&self) -> Result<usize, PeError> {
         self.virt_addr_to_sec_index(self.nt_hdr.opt_hdr.addr_of_entrypoint.val())
     }
 
-    pub fn entry_rel_sec_offset(&self) -> Result<usize, String> {
+    pub fn entry_rel_sec_offset(&self) -> Result<usize, PeError> {
         Ok(self.nt_hdr.opt_hdr.addr_of_entrypoint.val() as usize
             - self.entry_sec_ref()?.virt_addr.val() as usize)
     }
 
-    pub fn entry_sec_ref(&self) -> Result<&SectionHeader<'a>, String> {
+    pub fn entry_sec_ref(&self) -> Result<&SectionHeader<'a>, PeError> {
         Ok(&self.sec_hdrs[self.entry_sec_index()?])
     }
 
-    pub fn entry_sec_virt_ip(&self) -> Result<u64, String> {
-        Ok(self.nt_hdr.opt_hdr.image_base.val() + self.entry_sec_ref()?.virt_addr.val() as u64)
+    pub fn entry_sec_virt_ip(&self) -> Result<u64, PeError> {
+        Ok(self.image_base() + self.entry_sec_ref()?.virt_addr.val() as u64)
     }
 
-    pub fn entry_disk_offset(&self) -> Result<usize, String> {
+    pub fn entry_disk_offset(&self) -> Result<usize, PeError> {
         Ok(self.entry_sec_ref()?.ptr_to_raw_data.val() as usize + self.entry_rel_sec_offset()?)
     }
 
-    pub fn entry_sec_virt_size(&self) -> Result<u32, String> {
-        Ok(((self.entry_sec_ref()?.size_of_raw_data.val() / 0x1000) + 1) * 0x1000)
+    pub fn entry_sec_virt_size(&self) -> Result<u32, PeError> {
+        self.sec_virt_size(self.entry_sec_ref()?)
     }
 
-    pub fn sec_virt_size(size_of_raw_data: u32) -> u32 {
-        ((size_of_raw_data / 0x1000) + 1) * 0x1000
+    // Size a section takes up once mapped, the loader maps whichever is bigger of the virtual
+    // and raw sizes and rounds it up to the section alignment
+    pub fn sec_virt_size(&self, sec_hdr: &SectionHeader) -> Result<u32, PeError> {
+        let size = sec_hdr.virt_size.val().max(sec_hdr.size_of_raw_data.val());
+
+        align_up(size, self.nt_hdr.opt_hdr.sec_alignment.val())
     }
 }
 
@@ -131,7 +392,10 @@ fn virt_addr_to_sec_index() {
     pe_hdr.sec_hdrs[1].virt_addr.set(0x2000);
     pe_hdr.sec_hdrs[1].virt_size.set(0x1000);
 
-    assert_eq_hex!(pe_hdr.virt_addr_to_sec_index(0x0).ok(), None);
+    assert_eq!(
+        pe_hdr.virt_addr_to_sec_index(0x0),
+        Err(PeError::SectionNotFound { va: 0x0 })
+    );
     assert_eq_hex!(pe_hdr.virt_addr_to_sec_index(0x1000).ok(), Some(0));
     assert_eq_hex!(pe_hdr.virt_addr_to_sec_index(0x1500).ok(), Some(0));
     assert_eq_hex!(pe_hdr.virt_addr_to_sec_index(0x2000).ok(), Some(1));
@@ -220,8 +484,439 @@ pub fn entry_sec_virt_size() {
 
     pe_hdr.sec_hdrs[0].size_of_raw_data.set(0x1001);
     assert_eq_hex!(pe_hdr.entry_sec_virt_size().unwrap(), 0x2000);
+
+    pe_hdr.sec_hdrs[0].size_of_raw_data.set(0x1000);
+    assert_eq_hex!(pe_hdr.entry_sec_virt_size().unwrap(), 0x1000);
+
+    pe_hdr.sec_hdrs[0].virt_size.set(0x2345);
+    assert_eq_hex!(pe_hdr.entry_sec_virt_size().unwrap(), 0x3000);
+
+    pe_hdr.nt_hdr.opt_hdr.sec_alignment.set(0x200);
+    assert_eq_hex!(pe_hdr.entry_sec_virt_size().unwrap(), 0x2400);
+
+    // Rounding a size this close to u32::MAX up overflows
+    pe_hdr.sec_hdrs[1].virt_size.set(0xFFFF_FF01);
+    assert_eq!(
+        pe_hdr.sec_virt_size(&pe_hdr.sec_hdrs[1]),
+        Err(PeError::AddressOverflow)
+    );
+    assert_eq!(
+        pe_hdr.virt_addr_to_sec_index(0x4000),
+        Err(PeError::AddressOverflow)
+    );
+}
+
+#[test]
+fn rva_lookups_agree() {
+    let mut buf = read_test_pe();
+    let mut pe_hdr = PeHeader::new(&mut buf);
+
+    // .code has a virtual size of 0x4B but 0x200 bytes of raw data, all of it gets mapped
+    assert_eq_hex!(pe_hdr.virt_addr_to_sec_index(0x11FF).ok(), Some(0));
+    assert_eq_hex!(pe_hdr.rva_to_offset(0x11FF).ok(), Some(0x5FF));
+    assert_eq_hex!(pe_hdr.data_at_rva(0x11FF).map(|d| d.len()).ok(), Some(1));
+
+    // The rest of the page is mapped too, but has nothing on disk
+    assert_eq_hex!(pe_hdr.virt_addr_to_sec_index(0x1FFF).ok(), Some(0));
+    assert_eq!(
+        pe_hdr.data_at_rva(0x1200).map(|d| d.len()),
+        Err(PeError::RvaNotMapped { rva: 0x1200 })
+    );
+
+    // A virtual size of a whole page doesn't spill into the next section
+    pe_hdr.sec_hdrs[0].virt_size.set(0x1000);
+    assert_eq_hex!(pe_hdr.virt_addr_to_sec_index(0x2000).ok(), Some(1));
+}
+
+#[test]
+fn rva_to_va_overflow() {
+    let mut buf = read_test_pe();
+    let mut pe_hdr = PeHeader::new(&mut buf);
+    pe_hdr.set_image_base(0xFFFF_FFFF_FFFF_F000);
+
+    assert_eq!(
+        pe_hdr.rva_to_va(0x3020),
+        Err(PeError::RvaNotMapped { rva: 0x3020 })
+    );
+}
+
+#[test]
+fn address_translation() {
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let image_base = pe_hdr.image_base();
+
+    assert_eq_hex!(pe_hdr.rva_to_va(0x3020).unwrap(), image_base + 0x3020);
+    assert_eq_hex!(pe_hdr.va_to_rva(image_base + 0x3020).unwrap(), 0x3020);
+    assert_eq!(
+        pe_hdr.va_to_rva(image_base - 1),
+        Err(PeError::VaNotMapped { va: image_base - 1 })
+    );
+    assert_eq!(
+        pe_hdr.va_to_rva(image_base + 0x6000),
+        Err(PeError::VaNotMapped {
+            va: image_base + 0x6000
+        })
+    );
+    assert_eq!(
+        pe_hdr.rva_to_va(0x6000),
+        Err(PeError::RvaNotMapped { rva: 0x6000 })
+    );
+
+    // Headers map to the same offset, the rest of their page has nothing on disk
+    assert_eq_hex!(pe_hdr.rva_to_va(0x80).unwrap(), image_base + 0x80);
+    assert_eq_hex!(pe_hdr.rva_to_offset(0x80).unwrap(), 0x80);
+    assert_eq_hex!(pe_hdr.offset_to_rva(0x80).unwrap(), 0x80);
+    assert_eq!(
+        pe_hdr.rva_to_offset(0x400),
+        Err(PeError::RvaHasNoRawData { rva: 0x400 })
+    );
+
+    assert_eq_hex!(pe_hdr.rva_to_offset(0x3020).unwrap(), 0x820);
+    assert_eq_hex!(pe_hdr.offset_to_rva(0x820).unwrap(), 0x3020);
+    assert_eq_hex!(pe_hdr.offset_to_section(0x820).unwrap(), 2);
+    assert_eq_hex!(pe_hdr.rva_to_offset(0x51FF).unwrap(), 0xDFF);
+    assert_eq_hex!(pe_hdr.offset_to_rva(0xDFF).unwrap(), 0x51FF);
+    assert_eq!(
+        pe_hdr.rva_to_offset(0x5200),
+        Err(PeError::RvaHasNoRawData { rva: 0x5200 })
+    );
+    assert_eq!(
+        pe_hdr.rva_to_offset(0x6000),
+        Err(PeError::RvaNotMapped { rva: 0x6000 })
+    );
+
+    // Overlay
+    assert_eq!(
+        pe_hdr.offset_to_rva(0xE00),
+        Err(PeError::OffsetNotMapped { offset: 0xE00 })
+    );
+    assert_eq!(
+        pe_hdr.offset_to_section(0x80),
+        Err(PeError::OffsetNotMapped { offset: 0x80 })
+    );
+}
+
+#[test]
+fn try_new_valid() {
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq_hex!(pe_hdr.sec_hdrs.len(), 5);
+    assert_eq_hex!(pe_hdr.sec_ranges.len(), 5);
+    assert_eq_hex!(pe_hdr.body_offset, 0x290);
+    assert_eq!(pe_hdr.sec_ranges[0], Some(0x400..0x600));
+    assert_eq!(pe_hdr.sec_ranges[4], Some(0xC00..0xE00));
+    assert_eq_hex!(pe_hdr.sec(0).unwrap().len(), 0x200);
+}
+
+#[test]
+fn try_new_bad_dos_sig() {
+    let mut buf = read_test_pe();
+    buf[0] = 0;
+
+    assert_eq!(
+        PeHeader::try_new(&mut buf).err(),
+        Some(PeError::BadDosSignature { found: 0x5A00 })
+    );
+}
+
+#[test]
+fn try_new_bad_nt_sig() {
+    let mut buf = read_test_pe();
+    buf[0xC0] = 0;
+
+    assert_eq!(
+        PeHeader::try_new(&mut buf).err(),
+        Some(PeError::BadNtSignature { found: 0x4500 })
+    );
+}
+
+#[test]
+fn try_new_bad_new_exe_hdr_offset() {
+    let mut buf = read_test_pe();
+    buf[0x3C] = 0x20;
+
+    assert_eq!(
+        PeHeader::try_new(&mut buf).err(),
+        Some(PeError::BadNewExeHdrOffset { offset: 0x20 })
+    );
+
+    buf[0x3C..0x40].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+
+    assert_eq!(
+        PeHeader::try_new(&mut buf).err(),
+        Some(PeError::Truncated {
+            offset: 0xFFFF_FFF0,
+            needed: 0x1A
+        })
+    );
+}
+
+#[test]
+fn try_new_truncated() {
+    let mut buf = read_test_pe();
+
+    assert_eq!(
+        PeHeader::try_new(&mut buf[..0x20]).err(),
+        Some(PeError::Truncated {
+            offset: 0,
+            needed: 0x40
+        })
+    );
+
+    assert_eq!(
+        PeHeader::try_new(&mut buf[..0x1D0]).err(),
+        Some(PeError::Truncated {
+            offset: 0x1C8,
+            needed: 0xC8
+        })
+    );
+}
+
+#[test]
+fn try_new_bad_section_data() {
+    // Point .text raw data into the section table
+    let mut buf = read_test_pe();
+    buf[0x1F0 + 0x14] = 0x00;
+    buf[0x1F0 + 0x15] = 0x01;
+    assert_eq!(
+        PeHeader::try_new(&mut buf).err(),
+        Some(PeError::SectionDataOverlap { index: 1 })
+    );
+
+    // Point .text raw data past the end of the buffer
+    let mut buf = read_test_pe();
+    buf[0x1F0 + 0x15] = 0x10;
+    assert_eq!(
+        PeHeader::try_new(&mut buf).err(),
+        Some(PeError::SectionDataOutOfBounds { index: 1 })
+    );
+}
+
+#[test]
+fn read_at_rva() {
+    let mut buf = read_test_pe();
+    buf[0x9A0..0x9A6].copy_from_slice(&[b'z', 0, 0xE9, 0, b'o', 0]);
+    buf[0xDF0..0xE00].copy_from_slice(&[b'A'; 0x10]);
+    let pe_hdr = PeHeader::new(&mut buf);
+
+    assert_eq_hex!(*pe_hdr.read_at_rva(0x316E, 4).unwrap(), *b"KERN");
+    assert_eq_hex!(pe_hdr.read_u8_at_rva(0x3160).unwrap(), 0x66);
+    assert_eq_hex!(pe_hdr.read_u16_at_rva(0x317C).unwrap(), 0x285);
+    assert_eq_hex!(pe_hdr.read_u32_at_rva(0x3038).unwrap(), 0x83C);
+    assert_eq_hex!(pe_hdr.read_u64_at_rva(0x3038).unwrap(), 0x83C);
+    assert_eq!(pe_hdr.read_c_string_at_rva(0x316E).unwrap(), "KERNEL32.dll");
+    assert_eq!(pe_hdr.read_utf16_at_rva(0x31A0, 3).unwrap(), "z\u{E9}o");
+
+    // Reads can't run past the raw data of the section
+    assert_eq!(
+        pe_hdr.read_u32_at_rva(0x31FE),
+        Err(PeError::Truncated {
+            offset: 0x9FE,
+            needed: 4
+        })
+    );
+    assert_eq!(
+        pe_hdr.read_c_string_at_rva(0x51F0),
+        Err(PeError::Truncated {
+            offset: 0xE00,
+            needed: 1
+        })
+    );
+    assert_eq!(
+        pe_hdr.read_u8_at_rva(0x3200),
+        Err(PeError::RvaNotMapped { rva: 0x3200 })
+    );
+}
+
+#[test]
+fn sec_data_past_eof() {
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::try_new(&mut buf[..0xD00]).unwrap();
+
+    assert_eq!(pe_hdr.sec_ranges[4], Some(0xC00..0xD00));
+    assert_eq_hex!(pe_hdr.sec(4).unwrap().len(), 0x100);
+    assert_eq_hex!(pe_hdr.sec_hdrs[4].size_of_raw_data.val(), 0x200);
+}
+
+#[test]
+fn sec_data_uninit() {
+    // .data with no raw data, like a .bss section
+    let mut buf = read_test_pe();
+    buf[0x240 + 0x10..0x240 + 0x18].copy_from_slice(&[0; 8]);
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(pe_hdr.sec_ranges[3], None);
+    assert!(pe_hdr.sec(3).is_none());
+    assert!(pe_hdr.sec_mut(3).is_none());
+    assert_eq_hex!(pe_hdr.sec(4).unwrap().len(), 0x200);
+
+    // Zero raw size with a non zero pointer
+    let mut buf = read_test_pe();
+    buf[0x240 + 0x10..0x240 + 0x14].copy_from_slice(&[0; 4]);
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(pe_hdr.sec_ranges[3], None);
+}
+
+#[test]
+fn sec_data_overlap() {
+    // Alias the second half of .code as .text raw data
+    let mut buf = read_test_pe();
+    buf[0x1F0 + 0x15] = 0x05;
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(pe_hdr.sec_ranges[0], Some(0x400..0x600));
+    assert_eq!(pe_hdr.sec_ranges[1], Some(0x500..0x700));
+
+    pe_hdr.sec_mut(1).unwrap()[0] = 0xAA;
+    assert_eq_hex!(pe_hdr.sec(0).unwrap()[0x100], 0xAA);
+}
+
+#[test]
+fn data_at_rva() {
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq_hex!(pe_hdr.data_at_rva(0x3000).unwrap().len(), 0x200);
+    assert_eq_hex!(pe_hdr.data_at_rva(0x316E).unwrap()[..4], *b"KERN");
+    assert_eq_hex!(pe_hdr.data_at_rva(0x31FF).unwrap().len(), 1);
+    assert_eq!(
+        pe_hdr.data_at_rva(0x3200).err(),
+        Some(PeError::RvaNotMapped { rva: 0x3200 })
+    );
+    assert_eq!(
+        pe_hdr.data_at_rva(0x0).err(),
+        Some(PeError::RvaNotMapped { rva: 0x0 })
+    );
+}
+
+#[test]
+fn try_new_bad_opt_hdr_magic() {
+    let mut buf = read_test_pe();
+    buf[0xD8] = 0x0C;
+
+    assert_eq!(
+        PeHeader::try_new(&mut buf).err(),
+        Some(PeError::BadOptHdrMagic { found: 0x20C })
+    );
+}
+
+#[test]
+fn pe32_opt_hdr() {
+    let mut buf = read_test_pe32();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let opt_hdr = &pe_hdr.nt_hdr.opt_hdr;
+
+    assert!(!pe_hdr.is_pe32_plus());
+    assert_eq_hex!(pe_hdr.image_base(), 0x10000000);
+    assert_eq_hex!(opt_hdr.base_of_data.as_ref().map(|b| b.val()), Some(0x2000));
+    assert_eq_hex!(opt_hdr.sec_alignment.val(), 0x1000);
+    assert_eq_hex!(opt_hdr.file_alignment.val(), 0x200);
+    assert_eq_hex!(opt_hdr.size_of_image.val(), 0x4000);
+    assert_eq_hex!(opt_hdr.size_of_stack_reservee.val(), 0x100000);
+    assert_eq_hex!(opt_hdr.size_of_heap_commit.val(), 0x1000);
+    assert_eq_hex!(opt_hdr.num_of_rva_and_sizes.val(), 16);
+    assert_eq_hex!(pe_hdr.sec_hdrs.len(), 3);
+    assert_eq_hex!(pe_hdr.entry_sec_virt_ip().unwrap(), 0x10001000);
+}
+
+#[test]
+fn pe32_plus_opt_hdr() {
+    let mut buf = read_test_pe();
+    let mut pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert!(pe_hdr.is_pe32_plus());
+    assert_eq!(pe_hdr.nt_hdr.opt_hdr.base_of_data, None);
+    assert_eq_hex!(pe_hdr.image_base(), 0x400000);
+
+    pe_hdr.set_image_base(0x1_4000_0000);
+    assert_eq_hex!(pe_hdr.image_base(), 0x1_4000_0000);
+}
+
+#[test]
+fn sec_hdrs_table_order() {
+    // Swap the .code and .text headers so table order no longer matches raw data order
+    let mut buf = read_test_pe();
+    let code_hdr = buf[0x1C8..0x1F0].to_vec();
+    buf.copy_within(0x1F0..0x218, 0x1C8);
+    buf[0x1F0..0x218].copy_from_slice(&code_hdr);
+    buf[0x600] = 0xAA;
+
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(*pe_hdr.sec_hdrs[0].name.as_ref(), b".text\0\0\0");
+    assert_eq!(*pe_hdr.sec_hdrs[1].name.as_ref(), b".code\0\0\0");
+    assert_eq_hex!(pe_hdr.sec(0).unwrap()[0], 0xAA);
+    assert_eq_hex!(pe_hdr.raw_data_order(), [1, 0, 2, 3, 4]);
+}
+
+#[test]
+fn data_dirs() {
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let data_dirs = &pe_hdr.nt_hdr.opt_hdr.data_dirs;
+
+    assert!(data_dirs.export.is_some());
+    assert!(data_dirs.reserved.is_some());
+    assert_eq_hex!(data_dirs.import.as_ref().unwrap().virt_addr.val(), 0x3100);
+    assert_eq_hex!(data_dirs.import.as_ref().unwrap().size.val(), 0x3C);
+    assert_eq_hex!(
+        data_dirs.base_reloc.as_ref().unwrap().virt_addr.val(),
+        0x5000
+    );
+    assert_eq_hex!(data_dirs.iat.as_ref().unwrap().virt_addr.val(), 0x3000);
+
+    let mut buf = read_test_pe();
+    buf[0x144] = 6;
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    let data_dirs = &pe_hdr.nt_hdr.opt_hdr.data_dirs;
+
+    assert!(data_dirs.base_reloc.is_some());
+    assert!(data_dirs.debug.is_none());
+    assert!(data_dirs.iat.is_none());
+    assert_eq_hex!(pe_hdr.sec_hdrs.len(), 5);
+}
+
+#[test]
+fn opt_hdr_size() {
+    // Grow the optional header by 8 bytes, shifting the section table along with it
+    let mut buf = read_test_pe();
+    buf[0xD4] = 0xF8;
+    buf.copy_within(0x1C8..0x290, 0x1D0);
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(*pe_hdr.sec_hdrs[0].name.as_ref(), b".code\0\0\0");
+    assert_eq!(*pe_hdr.sec_hdrs[4].name.as_ref(), b".reloc\0\0");
+    assert!(pe_hdr.nt_hdr.opt_hdr.data_dirs.reserved.is_some());
+
+    // Shrink it so only 14 data directories fit
+    let mut buf = read_test_pe();
+    buf[0xD4] = 0xE0;
+    buf.copy_within(0x1C8..0x290, 0x1B8);
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(*pe_hdr.sec_hdrs[0].name.as_ref(), b".code\0\0\0");
+    assert!(pe_hdr.nt_hdr.opt_hdr.data_dirs.delay_import.is_some());
+    assert!(pe_hdr.nt_hdr.opt_hdr.data_dirs.com_descriptor.is_none());
+
+    let mut buf = read_test_pe();
+    buf[0xD4] = 0x6F;
+    assert_eq!(
+        PeHeader::try_new(&mut buf).err(),
+        Some(PeError::BadOptHdrSize { size: 0x6F })
+    );
+}
+
+pub fn read_test_pe32() -> Vec<u8> {
+    std::fs::read("test_data/test_pe32.dll").unwrap()
 }
 
 pub fn read_test_pe() -> Vec<u8> {
     std::fs::read("test_data/test_pe.exe").unwrap()
 }
+
+pub fn read_test_pe64() -> Vec<u8> {
+    std::fs::read("test_data/test_pe64.dll").unwrap()
+}
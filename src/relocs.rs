@@ -2,31 +2,100 @@
 #[macro_use]
 #[allow(unused_imports)]
 use assert_hex::assert_eq_hex;
-use crate::util::{IterWriteBack, ROCursor, RWCursor};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::error::PeError;
+use crate::nt_hdr::{
+    DataDirType, IMAGE_FILE_MACHINE_ARM, IMAGE_FILE_MACHINE_ARMNT, IMAGE_FILE_MACHINE_LOONGARCH32,
+    IMAGE_FILE_MACHINE_LOONGARCH64, IMAGE_FILE_MACHINE_RISCV128, IMAGE_FILE_MACHINE_RISCV32,
+    IMAGE_FILE_MACHINE_RISCV64, IMAGE_FILE_MACHINE_THUMB,
+};
+use crate::pe::PeHeader;
+use crate::sec_hdr::{
+    IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_DISCARDABLE, IMAGE_SCN_MEM_READ,
+};
+use crate::sections::append_section;
+use crate::util::{align_up, IterWriteBack, ROCursor, RWCursor};
+use alloc::collections::BTreeMap;
 use alloc::prelude::v1::*;
+use byteorder::{ByteOrder, LittleEndian};
+use zordon::prelude::*;
+
+pub const RELOC_SEC_NAME: &[u8] = b".reloc2";
+pub const RELOC_PAGE_SIZE: u32 = 0x1000;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RelocationType {
-    // For now skip/ignore the other types
     ImageRelBasedAbsolute,
     ImageRelBasedHigh,
     ImageRelBasedLow,
     ImageRelBasedHighLow,
     ImageRelBasedHighAdj,
     ImageRelBasedMipsJmpAddr,
+    ImageRelBasedArmMov32,
+    ImageRelBasedRiscvHigh20,
+    ImageRelBasedThumbMov32,
+    ImageRelBasedRiscvLow12I,
+    ImageRelBasedRiscvLow12S,
+    ImageRelBasedLoongArch32MarkLa,
+    ImageRelBasedLoongArch64MarkLa,
+    ImageRelBasedMipsJmpAddr16,
+    ImageRelBasedDir64,
+    Unknown(u8),
 }
 
 impl RelocationType {
+    // Types 5, 7 and 8 mean different things depending on the machine, without one they are
+    // read as the MIPS/x86 meaning where there is one and Unknown otherwise
     pub fn new(reloc_type: u8) -> Self {
+        Self::for_machine(reloc_type, 0)
+    }
+
+    pub fn for_machine(reloc_type: u8, machine: u16) -> Self {
+        let arm = matches!(
+            machine,
+            IMAGE_FILE_MACHINE_ARM | IMAGE_FILE_MACHINE_THUMB | IMAGE_FILE_MACHINE_ARMNT
+        );
+        let riscv = matches!(
+            machine,
+            IMAGE_FILE_MACHINE_RISCV32 | IMAGE_FILE_MACHINE_RISCV64 | IMAGE_FILE_MACHINE_RISCV128
+        );
+
         match reloc_type {
             0 => Self::ImageRelBasedAbsolute,
             1 => Self::ImageRelBasedHigh,
             2 => Self::ImageRelBasedLow,
             3 => Self::ImageRelBasedHighLow,
             4 => Self::ImageRelBasedHighAdj,
+            5 if arm => Self::ImageRelBasedArmMov32,
+            5 if riscv => Self::ImageRelBasedRiscvHigh20,
             5 => Self::ImageRelBasedMipsJmpAddr,
-            _ => unimplemented!("reloc_type: {}", reloc_type),
+            7 if arm => Self::ImageRelBasedThumbMov32,
+            7 if riscv => Self::ImageRelBasedRiscvLow12I,
+            8 if riscv => Self::ImageRelBasedRiscvLow12S,
+            8 if machine == IMAGE_FILE_MACHINE_LOONGARCH32 => Self::ImageRelBasedLoongArch32MarkLa,
+            8 if machine == IMAGE_FILE_MACHINE_LOONGARCH64 => Self::ImageRelBasedLoongArch64MarkLa,
+            9 => Self::ImageRelBasedMipsJmpAddr16,
+            10 => Self::ImageRelBasedDir64,
+            _ => Self::Unknown(reloc_type),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::ImageRelBasedAbsolute => 0,
+            Self::ImageRelBasedHigh => 1,
+            Self::ImageRelBasedLow => 2,
+            Self::ImageRelBasedHighLow => 3,
+            Self::ImageRelBasedHighAdj => 4,
+            Self::ImageRelBasedMipsJmpAddr
+            | Self::ImageRelBasedArmMov32
+            | Self::ImageRelBasedRiscvHigh20 => 5,
+            Self::ImageRelBasedThumbMov32 | Self::ImageRelBasedRiscvLow12I => 7,
+            Self::ImageRelBasedRiscvLow12S
+            | Self::ImageRelBasedLoongArch32MarkLa
+            | Self::ImageRelBasedLoongArch64MarkLa => 8,
+            Self::ImageRelBasedMipsJmpAddr16 => 9,
+            Self::ImageRelBasedDir64 => 10,
+            Self::Unknown(reloc_type) => reloc_type,
         }
     }
 }
@@ -39,14 +108,21 @@ pub struct RelocTypeOffset {
 
 impl RelocTypeOffset {
     pub fn new(type_offset_pair: u16) -> Self {
+        Self::for_machine(type_offset_pair, 0)
+    }
+
+    pub fn for_machine(type_offset_pair: u16, machine: u16) -> Self {
         Self {
-            reloc_type: RelocationType::new(((type_offset_pair & 0xF000) >> 12) as u8),
-            reloc_offset: (type_offset_pair & 0xFFF) as u16,
+            reloc_type: RelocationType::for_machine(
+                ((type_offset_pair & 0xF000) >> 12) as u8,
+                machine,
+            ),
+            reloc_offset: type_offset_pair & 0xFFF,
         }
     }
 
     pub fn to_u16le(&self) -> u16 {
-        (self.reloc_type as u16) << 12 | self.reloc_offset as u16
+        (self.reloc_type.to_u8() as u16) << 12 | self.reloc_offset
     }
 }
 
@@ -59,42 +135,73 @@ pub struct Relocation {
 
 pub struct RelocationsIter<'a> {
     buf: ROCursor<'a>,
+    machine: u16,
+    done: bool,
 }
 
 impl<'a> RelocationsIter<'a> {
     pub fn new(buf: &'a [u8]) -> Self {
+        Self::for_machine(buf, 0)
+    }
+
+    pub fn for_machine(buf: &'a [u8], machine: u16) -> Self {
         Self {
             buf: ROCursor::new(buf),
+            machine,
+            done: false,
         }
     }
-}
 
-impl<'a> Iterator for RelocationsIter<'a> {
-    type Item = Relocation;
+    fn read_next(&mut self) -> Result<Option<Relocation>, PeError> {
+        if self.buf.remaining() == 0 {
+            return Ok(None);
+        }
 
-    // Maybe this shouldn't panic
-    fn next(&mut self) -> Option<Self::Item> {
-        let virt_addr = self.buf.read_u32::<LittleEndian>();
+        let offset = self.buf.position();
+        let virt_addr = self.buf.read_u32::<LittleEndian>()?;
 
         if virt_addr == 0 {
-            return None;
+            return Ok(None);
         }
 
-        let size_of_block = self.buf.read_u32::<LittleEndian>();
-        let type_offset_count = ((size_of_block - 8) / 2) as usize;
+        let size_of_block = self.buf.read_u32::<LittleEndian>()?;
 
-        let mut block: Vec<RelocTypeOffset> = Vec::with_capacity(type_offset_count);
+        if size_of_block < 8 {
+            return Err(PeError::BadRelocBlockSize {
+                offset,
+                size: size_of_block,
+            });
+        }
+
+        let type_offset_count = ((size_of_block - 8) / 2) as usize;
+        let mut block: Vec<RelocTypeOffset> =
+            Vec::with_capacity(type_offset_count.min(self.buf.remaining() / 2));
 
         for _ in 1..=type_offset_count {
-            let type_offset_pair = self.buf.read_u16::<LittleEndian>();
-            block.push(RelocTypeOffset::new(type_offset_pair));
+            let type_offset_pair = self.buf.read_u16::<LittleEndian>()?;
+            block.push(RelocTypeOffset::for_machine(type_offset_pair, self.machine));
         }
 
-        Some(Relocation {
+        Ok(Some(Relocation {
             virt_addr,
             size_of_block,
             block,
-        })
+        }))
+    }
+}
+
+impl<'a> Iterator for RelocationsIter<'a> {
+    type Item = Result<Relocation, PeError>;
+
+    // Ends with the buffer or at a zero page, or with an error if a block is truncated
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let r = self.read_next().transpose();
+        self.done = !matches!(r, Some(Ok(_)));
+        r
     }
 }
 
@@ -108,12 +215,418 @@ impl<'a> IterWriteBack<'a> for Relocations {
         RelocationsIter::into_iter(RelocationsIter::new(buf))
     }
 
-    fn write_single(buf: &mut RWCursor, reloc: &Self::Output) {
-        buf.write_u32::<LittleEndian>(reloc.virt_addr);
-        buf.write_u32::<LittleEndian>(reloc.size_of_block);
+    fn write_single(buf: &mut RWCursor, reloc: &Self::Output) -> Result<(), PeError> {
+        buf.write_u32::<LittleEndian>(reloc.virt_addr)?;
+        buf.write_u32::<LittleEndian>(reloc.size_of_block)?;
         for e in &reloc.block {
-            buf.write_u16::<LittleEndian>(e.to_u16le())
+            buf.write_u16::<LittleEndian>(e.to_u16le())?;
+        }
+
+        Ok(())
+    }
+}
+
+enum Fixup {
+    High,
+    Low,
+    HighLow,
+    HighAdj(u16),
+    Dir64,
+}
+
+impl Fixup {
+    fn width(&self) -> usize {
+        match self {
+            Self::High | Self::Low | Self::HighAdj(_) => 2,
+            Self::HighLow => 4,
+            Self::Dir64 => 8,
+        }
+    }
+
+    fn apply(&self, buf: &mut [u8], delta: u64) {
+        match self {
+            Self::High => {
+                let v = ((LittleEndian::read_u16(buf) as u32) << 16).wrapping_add(delta as u32);
+                LittleEndian::write_u16(buf, (v >> 16) as u16);
+            }
+            Self::Low => {
+                let v = LittleEndian::read_u16(buf).wrapping_add(delta as u16);
+                LittleEndian::write_u16(buf, v);
+            }
+            Self::HighLow => {
+                let v = LittleEndian::read_u32(buf).wrapping_add(delta as u32);
+                LittleEndian::write_u32(buf, v);
+            }
+            // The low half comes from the following entry and is sign extended, the high half
+            // is rounded by it
+            Self::HighAdj(low) => {
+                let v = ((LittleEndian::read_u16(buf) as u32) << 16)
+                    .wrapping_add(*low as i16 as u32)
+                    .wrapping_add(delta as u32)
+                    .wrapping_add(0x8000);
+                LittleEndian::write_u16(buf, (v >> 16) as u16);
+            }
+            Self::Dir64 => {
+                let v = LittleEndian::read_u64(buf).wrapping_add(delta);
+                LittleEndian::write_u64(buf, v);
+            }
+        }
+    }
+}
+
+impl<'a> PeHeader<'a> {
+    pub fn relocations(&self) -> Result<Vec<Relocation>, PeError> {
+        let (rva, size) = self
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Reloc)
+            .ok_or(PeError::DataDirNotPresent {
+                dir: DataDirType::Reloc,
+            })?;
+
+        let data = self.data_at_rva(rva)?;
+        let machine = self.nt_hdr.file_hdr.machine.val();
+
+        RelocationsIter::for_machine(&data[..data.len().min(size as usize)], machine).collect()
+    }
+
+    // Patches every relocation target for new_base and updates image_base. All targets are
+    // checked before anything is written so a bad table leaves the image untouched. new_base has
+    // to be 64K aligned and, for PE32, fit in 32 bits
+    pub fn rebase(&mut self, new_base: u64) -> Result<(), PeError> {
+        if new_base & 0xFFFF != 0 || (!self.is_pe32_plus() && new_base > u32::MAX as u64) {
+            return Err(PeError::BadImageBase { base: new_base });
+        }
+
+        let delta = new_base.wrapping_sub(self.image_base());
+        let mut fixups: Vec<(u32, Fixup)> = Vec::new();
+
+        for r in self.relocations()?.iter() {
+            let mut entries = r.block.iter();
+
+            while let Some(e) = entries.next() {
+                let rva = r
+                    .virt_addr
+                    .checked_add(e.reloc_offset as u32)
+                    .ok_or(PeError::RvaNotMapped { rva: r.virt_addr })?;
+
+                let fixup = match e.reloc_type {
+                    RelocationType::ImageRelBasedAbsolute => continue,
+                    RelocationType::ImageRelBasedHigh => Fixup::High,
+                    RelocationType::ImageRelBasedLow => Fixup::Low,
+                    RelocationType::ImageRelBasedHighLow => Fixup::HighLow,
+                    RelocationType::ImageRelBasedDir64 => Fixup::Dir64,
+                    RelocationType::ImageRelBasedHighAdj => match entries.next() {
+                        Some(low) => Fixup::HighAdj(low.to_u16le()),
+                        None => return Err(PeError::RelocMissingHighAdjParam { rva }),
+                    },
+                    t => {
+                        return Err(PeError::UnsupportedRelocType {
+                            reloc_type: t.to_u8(),
+                        })
+                    }
+                };
+
+                if self.data_at_rva(rva)?.len() < fixup.width() {
+                    return Err(PeError::RvaNotMapped { rva });
+                }
+
+                fixups.push((rva, fixup));
+            }
         }
+
+        for (rva, fixup) in fixups.iter() {
+            fixup.apply(&mut self.data_at_rva_mut(*rva)?, delta);
+        }
+
+        self.set_image_base(new_base);
+
+        Ok(())
+    }
+}
+
+// Relocations keyed by target rva, regrouped into page blocks when built. HIGHADJ entries keep
+// the low half that follows them in the table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RelocationSet {
+    entries: BTreeMap<u32, RelocationType>,
+    high_adj_params: BTreeMap<u32, u16>,
+}
+
+impl RelocationSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_relocations(relocs: &[Relocation]) -> Result<Self, PeError> {
+        let mut set = Self::new();
+
+        for r in relocs.iter() {
+            let mut entries = r.block.iter();
+
+            while let Some(e) = entries.next() {
+                let rva = r
+                    .virt_addr
+                    .checked_add(e.reloc_offset as u32)
+                    .ok_or(PeError::RvaNotMapped { rva: r.virt_addr })?;
+
+                match e.reloc_type {
+                    RelocationType::ImageRelBasedAbsolute => (),
+                    RelocationType::ImageRelBasedHighAdj => {
+                        let low = entries.next().map(|l| l.to_u16le()).unwrap_or(0);
+                        set.insert_high_adj(rva, low);
+                    }
+                    t => {
+                        set.insert(rva, t);
+                    }
+                }
+            }
+        }
+
+        Ok(set)
+    }
+
+    pub fn from_pe(pe_hdr: &PeHeader) -> Result<Self, PeError> {
+        Self::from_relocations(&pe_hdr.relocations()?)
+    }
+
+    pub fn insert(&mut self, rva: u32, reloc_type: RelocationType) -> Option<RelocationType> {
+        self.high_adj_params.remove(&rva);
+        self.entries.insert(rva, reloc_type)
+    }
+
+    pub fn insert_high_adj(&mut self, rva: u32, low: u16) -> Option<RelocationType> {
+        self.high_adj_params.insert(rva, low);
+        self.entries
+            .insert(rva, RelocationType::ImageRelBasedHighAdj)
+    }
+
+    pub fn remove(&mut self, rva: u32) -> Option<RelocationType> {
+        self.high_adj_params.remove(&rva);
+        self.entries.remove(&rva)
+    }
+
+    // Removes every relocation targeting [start, end), e.g. code that has been overwritten
+    pub fn remove_range(&mut self, start: u32, end: u32) -> usize {
+        let rvas: Vec<u32> = self
+            .entries
+            .range(start..end)
+            .map(|(rva, _)| *rva)
+            .collect();
+
+        for rva in rvas.iter() {
+            self.remove(*rva);
+        }
+
+        rvas.len()
+    }
+
+    pub fn get(&self, rva: u32) -> Option<RelocationType> {
+        self.entries.get(&rva).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, RelocationType)> + '_ {
+        self.entries.iter().map(|(rva, t)| (*rva, *t))
+    }
+
+    // One block per page, padded with an absolute entry to keep each block 4 byte aligned
+    pub fn to_relocations(&self) -> Vec<Relocation> {
+        let mut relocs: Vec<Relocation> = Vec::new();
+
+        for (rva, reloc_type) in self.iter() {
+            let page = rva & !(RELOC_PAGE_SIZE - 1);
+
+            if relocs.last().map(|r| r.virt_addr) != Some(page) {
+                relocs.push(Relocation {
+                    virt_addr: page,
+                    size_of_block: 0,
+                    block: Vec::new(),
+                });
+            }
+
+            let r = relocs.last_mut().unwrap();
+            r.block.push(RelocTypeOffset {
+                reloc_type,
+                reloc_offset: (rva - page) as u16,
+            });
+
+            if let Some(low) = self.high_adj_params.get(&rva) {
+                r.block.push(RelocTypeOffset::new(*low));
+            }
+        }
+
+        for r in relocs.iter_mut() {
+            if r.block.len() % 2 != 0 {
+                r.block.push(RelocTypeOffset {
+                    reloc_type: RelocationType::ImageRelBasedAbsolute,
+                    reloc_offset: 0,
+                });
+            }
+
+            r.size_of_block = (8 + r.block.len() * 2) as u32;
+        }
+
+        relocs
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let relocs = self.to_relocations();
+        let mut data = vec![0u8; relocs.iter().map(|r| r.size_of_block as usize).sum()];
+
+        // Sized from the blocks themselves so this can't run out of room
+        Relocations::write_all(&mut RWCursor::new(&mut data), &relocs).unwrap();
+
+        data
+    }
+
+    // Writes the table over the existing one when it fits in the old size. When the old one is the
+    // last thing in its section it can also use the rest of the raw data, or grow the section if
+    // that is the last section both on disk and in memory. Otherwise a new section is appended
+    pub fn write(&self, buf: &[u8]) -> Result<Vec<u8>, PeError> {
+        let data = self.build();
+        let mut out = buf.to_vec();
+
+        let (old, grow) = {
+            let pe_hdr = PeHeader::try_new(&mut out)?;
+            let data_dirs = &pe_hdr.nt_hdr.opt_hdr.data_dirs;
+
+            if data_dirs.base_reloc.is_none() {
+                return Err(PeError::DataDirNotPresent {
+                    dir: DataDirType::Reloc,
+                });
+            }
+
+            match data_dirs.rva_and_size(DataDirType::Reloc) {
+                Some((rva, size)) => {
+                    let room = pe_hdr.data_at_rva(rva).map(|d| d.len()).unwrap_or(0);
+                    let next_sec_virt_addr = pe_hdr.next_sec_virt_addr()?;
+
+                    // Past the section's virtual size there is only padding, so a table reaching
+                    // it has nothing after it to overwrite
+                    let last = pe_hdr.virt_addr_to_sec_index(rva).ok().filter(|i| {
+                        let s = &pe_hdr.sec_hdrs[*i];
+                        rva as u64 + size as u64
+                            >= s.virt_addr.val() as u64 + s.virt_size.val() as u64
+                    });
+                    let grow = last.filter(|i| {
+                        let s = &pe_hdr.sec_hdrs[*i];
+
+                        pe_hdr.sec_ranges[*i].as_ref().map(|r| r.end) == Some(buf.len())
+                            && pe_hdr
+                                .sec_virt_size(s)
+                                .ok()
+                                .and_then(|size| s.virt_addr.val().checked_add(size))
+                                == Some(next_sec_virt_addr)
+                    });
+
+                    (Some((rva, size as usize, room, last.is_some())), grow)
+                }
+                None => (None, None),
+            }
+        };
+
+        let rva = match (old, grow) {
+            (Some((rva, size, room, last)), _)
+                if data.len() <= size || (last && data.len() <= room) =>
+            {
+                let mut pe_hdr = PeHeader::try_new(&mut out)?;
+
+                {
+                    let mut dst = pe_hdr.data_at_rva_mut(rva)?;
+                    let old_end = size.min(room).max(data.len());
+
+                    dst[..data.len()].copy_from_slice(&data);
+
+                    for b in dst[data.len()..old_end].iter_mut() {
+                        *b = 0;
+                    }
+                }
+
+                let i = pe_hdr.virt_addr_to_sec_index(rva)?;
+                let s = &mut pe_hdr.sec_hdrs[i];
+                let end = rva - s.virt_addr.val() + data.len() as u32;
+
+                if end > s.virt_size.val() {
+                    s.virt_size.set(end);
+                }
+
+                rva
+            }
+            (Some((rva, _, _, _)), Some(i)) => {
+                let (ptr, sec_offset, file_alignment) = {
+                    let pe_hdr = PeHeader::try_new(&mut out)?;
+                    let s = &pe_hdr.sec_hdrs[i];
+
+                    (
+                        s.ptr_to_raw_data.val() as usize,
+                        (rva - s.virt_addr.val()) as usize,
+                        pe_hdr.nt_hdr.opt_hdr.file_alignment.val(),
+                    )
+                };
+
+                let size_of_raw_data = align_up((sec_offset + data.len()) as u32, file_alignment)?;
+                out.resize(ptr + size_of_raw_data as usize, 0);
+                out[ptr + sec_offset..ptr + sec_offset + data.len()].copy_from_slice(&data);
+
+                let mut pe_hdr = PeHeader::try_new(&mut out)?;
+                let s = &mut pe_hdr.sec_hdrs[i];
+
+                // The old size_of_raw_data can run past the end of the file, in which case the
+                // section may not have grown at all
+                let grown_by = size_of_raw_data.saturating_sub(s.size_of_raw_data.val());
+
+                if s.characteristics.val() & IMAGE_SCN_CNT_INITIALIZED_DATA != 0 {
+                    let opt_hdr = &mut pe_hdr.nt_hdr.opt_hdr;
+                    let size_of_init_data = opt_hdr
+                        .size_of_init_data
+                        .val()
+                        .checked_add(grown_by)
+                        .ok_or(PeError::AddressOverflow)?;
+                    opt_hdr.size_of_init_data.set(size_of_init_data);
+                }
+
+                let s = &mut pe_hdr.sec_hdrs[i];
+                let virt_size = s.virt_size.val().max((sec_offset + data.len()) as u32);
+                s.virt_size.set(virt_size);
+                s.size_of_raw_data.set(size_of_raw_data);
+
+                let size_of_image = pe_hdr.next_sec_virt_addr()?;
+                pe_hdr.nt_hdr.opt_hdr.size_of_image.set(size_of_image);
+
+                rva
+            }
+            _ => {
+                let rva = PeHeader::try_new(&mut out)?.next_sec_virt_addr()?;
+                out = append_section(
+                    &out,
+                    RELOC_SEC_NAME,
+                    IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_DISCARDABLE,
+                    &data,
+                )?;
+
+                rva
+            }
+        };
+
+        {
+            let mut pe_hdr = PeHeader::try_new(&mut out)?;
+
+            if let Some(reloc_dir) = pe_hdr.nt_hdr.opt_hdr.data_dirs.base_reloc.as_mut() {
+                reloc_dir.virt_addr.set(rva);
+                reloc_dir.size.set(data.len() as u32);
+            }
+        }
+
+        Ok(out)
     }
 }
 
@@ -125,7 +638,7 @@ const RELOC_TESTDATA: [u8; 28] = [
 
 #[cfg(feature = "std_unit_tests")]
 #[cfg(test)]
-use alloc::prelude::v1::*;
+use crate::pe::{read_test_pe, read_test_pe32};
 
 #[test]
 fn relocations_iter() {
@@ -133,7 +646,7 @@ fn relocations_iter() {
     let mut relocs: Vec<Relocation> = Vec::with_capacity(2);
 
     for r in relocs_iter.into_iter() {
-        relocs.push(r);
+        relocs.push(r.unwrap());
     }
 
     assert_eq_hex!(relocs[0].virt_addr, 0x1000);
@@ -179,10 +692,392 @@ fn relocations_writeback() {
     let mut relocs: Vec<Relocation> = Vec::with_capacity(2);
 
     for r in relocs_iter.into_iter() {
-        relocs.push(r);
+        relocs.push(r.unwrap());
     }
 
-    Relocations::write_all(&mut relocs_write_buf, &relocs);
+    Relocations::write_all(&mut relocs_write_buf, &relocs).unwrap();
 
     assert_eq!(RELOC_TESTDATA, relocs_write_buf.buf);
 }
+
+#[test]
+fn reloc_types() {
+    use crate::nt_hdr::IMAGE_FILE_MACHINE_AMD64;
+
+    let machines = [
+        0,
+        IMAGE_FILE_MACHINE_AMD64,
+        IMAGE_FILE_MACHINE_ARMNT,
+        IMAGE_FILE_MACHINE_RISCV64,
+        IMAGE_FILE_MACHINE_LOONGARCH32,
+        IMAGE_FILE_MACHINE_LOONGARCH64,
+    ];
+
+    for machine in machines.iter() {
+        for type_offset_pair in (0..=0xFu16).map(|t| t << 12 | 0xABC) {
+            let e = RelocTypeOffset::for_machine(type_offset_pair, *machine);
+            assert_eq_hex!(e.to_u16le(), type_offset_pair);
+        }
+    }
+
+    assert_eq!(RelocationType::new(10), RelocationType::ImageRelBasedDir64);
+    assert_eq!(
+        RelocationType::new(5),
+        RelocationType::ImageRelBasedMipsJmpAddr
+    );
+    assert_eq!(RelocationType::new(6), RelocationType::Unknown(6));
+    assert_eq!(RelocationType::new(7), RelocationType::Unknown(7));
+    assert_eq!(
+        RelocationType::for_machine(5, IMAGE_FILE_MACHINE_ARMNT),
+        RelocationType::ImageRelBasedArmMov32
+    );
+    assert_eq!(
+        RelocationType::for_machine(7, IMAGE_FILE_MACHINE_ARMNT),
+        RelocationType::ImageRelBasedThumbMov32
+    );
+    assert_eq!(
+        RelocationType::for_machine(5, IMAGE_FILE_MACHINE_RISCV64),
+        RelocationType::ImageRelBasedRiscvHigh20
+    );
+    assert_eq!(
+        RelocationType::for_machine(7, IMAGE_FILE_MACHINE_RISCV64),
+        RelocationType::ImageRelBasedRiscvLow12I
+    );
+    assert_eq!(
+        RelocationType::for_machine(8, IMAGE_FILE_MACHINE_RISCV64),
+        RelocationType::ImageRelBasedRiscvLow12S
+    );
+    assert_eq!(
+        RelocationType::for_machine(8, IMAGE_FILE_MACHINE_LOONGARCH64),
+        RelocationType::ImageRelBasedLoongArch64MarkLa
+    );
+}
+
+#[test]
+fn relocations_iter_truncated() {
+    let relocs: Vec<Result<Relocation, PeError>> =
+        RelocationsIter::new(&RELOC_TESTDATA[..10]).collect();
+
+    assert_eq!(relocs.len(), 1);
+    assert_eq!(
+        relocs[0].as_ref().err(),
+        Some(&PeError::Truncated {
+            offset: 10,
+            needed: 2
+        })
+    );
+
+    let buf = [0, 0x10, 0, 0, 0x04, 0, 0, 0];
+    let relocs: Vec<Result<Relocation, PeError>> = RelocationsIter::new(&buf).collect();
+
+    assert_eq!(relocs.len(), 1);
+    assert_eq!(
+        relocs[0].as_ref().err(),
+        Some(&PeError::BadRelocBlockSize { offset: 0, size: 4 })
+    );
+}
+
+#[test]
+fn relocations_iter_dir64() {
+    let buf = [
+        0, 0x20, 0, 0, 0x10, 0, 0, 0, 0x08, 0xA0, 0x10, 0xA0, 0x18, 0x60, 0, 0, 0, 0, 0, 0,
+    ];
+    let relocs: Vec<Relocation> = RelocationsIter::new(&buf).map(|r| r.unwrap()).collect();
+
+    assert_eq!(relocs.len(), 1);
+    assert_eq_hex!(relocs[0].virt_addr, 0x2000);
+    assert_eq!(
+        relocs[0].block[0].reloc_type,
+        RelocationType::ImageRelBasedDir64
+    );
+    assert_eq!(relocs[0].block[1].reloc_offset, 0x10);
+    assert_eq!(relocs[0].block[2].reloc_type, RelocationType::Unknown(6));
+    assert_eq!(
+        relocs[0].block[3].reloc_type,
+        RelocationType::ImageRelBasedAbsolute
+    );
+}
+
+#[test]
+fn rebase_pe32() {
+    let orig = read_test_pe32();
+    let mut buf = orig.clone();
+    let mut pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    pe_hdr.rebase(0x2000_0000).unwrap();
+    assert_eq_hex!(pe_hdr.image_base(), 0x2000_0000);
+
+    {
+        let code = pe_hdr.sec(0).unwrap();
+        assert_eq_hex!(LittleEndian::read_u32(&code[0x01..]), 0x2000_2000);
+        assert_eq_hex!(LittleEndian::read_u32(&code[0x07..]), 0x2000_2010);
+    }
+
+    // Only the two relocated dwords and image_base should differ from the original
+    let diff: Vec<usize> = (0..orig.len()).filter(|i| orig[*i] != buf[*i]).collect();
+    assert_eq!(diff, [0xB7, 0x204, 0x20A]);
+
+    let expected = std::fs::read("test_data/test_pe32_rebased.dll").unwrap();
+    assert_eq_hex!(buf, expected);
+}
+
+#[test]
+fn rebase_bad_base() {
+    let orig = read_test_pe32();
+    let mut buf = orig.clone();
+    let mut pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(
+        pe_hdr.rebase(0x2000_8000),
+        Err(PeError::BadImageBase { base: 0x2000_8000 })
+    );
+    assert_eq!(
+        pe_hdr.rebase(0x1_0000_0000),
+        Err(PeError::BadImageBase {
+            base: 0x1_0000_0000
+        })
+    );
+    drop(pe_hdr);
+
+    assert_eq!(buf, orig);
+
+    // PE32+ takes bases above 4GB
+    let mut buf = read_test_pe();
+    let mut pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    pe_hdr.rebase(0x1_4000_0000).unwrap();
+    assert_eq_hex!(pe_hdr.image_base(), 0x1_4000_0000);
+}
+
+#[test]
+fn rebase_reloc_types() {
+    let mut buf = read_test_pe32();
+
+    // Replace the .reloc block with one entry of each supported type targeting spare .rdata
+    let block = [
+        0x00, 0x20, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0xA0, 0xA1, 0xA8, 0x11, 0xAA, 0x21, 0xAC,
+        0x41, 0x00, 0x80, 0x00, 0x00,
+    ];
+    buf[0x600..0x614].copy_from_slice(&block);
+    LittleEndian::write_u32(&mut buf[0x124..], block.len() as u32);
+    LittleEndian::write_u64(&mut buf[0x5A0..], 0x1000_1000);
+    LittleEndian::write_u16(&mut buf[0x5A8..], 0x1000);
+    LittleEndian::write_u16(&mut buf[0x5AA..], 0x2000);
+    LittleEndian::write_u16(&mut buf[0x5AC..], 0x1000);
+
+    let mut pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    pe_hdr.rebase(0x2001_0000).unwrap();
+
+    let rdata = pe_hdr.sec(1).unwrap();
+    assert_eq_hex!(LittleEndian::read_u64(&rdata[0x1A0..]), 0x2001_1000);
+    assert_eq_hex!(LittleEndian::read_u16(&rdata[0x1A8..]), 0x2001);
+    assert_eq_hex!(LittleEndian::read_u16(&rdata[0x1AA..]), 0x2000);
+    assert_eq_hex!(LittleEndian::read_u16(&rdata[0x1AC..]), 0x2001);
+}
+
+#[test]
+fn rebase_unsupported() {
+    let orig = read_test_pe32();
+    let mut buf = orig.clone();
+
+    // ARM_MOV32 on an x86 image reads as MIPS_JMPADDR which isn't handled
+    buf[0x609] = 0x50;
+
+    let mut pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    assert_eq!(
+        pe_hdr.rebase(0x2000_0000),
+        Err(PeError::UnsupportedRelocType { reloc_type: 5 })
+    );
+    assert_eq_hex!(pe_hdr.image_base(), 0x1000_0000);
+    drop(pe_hdr);
+
+    assert_eq!(&buf[..0x600], &orig[..0x600]);
+
+    // A block whose page rva plus entry offset runs past u32::MAX
+    let mut buf = orig.clone();
+    LittleEndian::write_u32(&mut buf[0x600..], 0xFFFF_FFFF);
+
+    let mut pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+    assert_eq!(
+        pe_hdr.rebase(0x2000_0000),
+        Err(PeError::RvaNotMapped { rva: 0xFFFF_FFFF })
+    );
+    assert_eq_hex!(pe_hdr.image_base(), 0x1000_0000);
+}
+
+#[test]
+fn reloc_set_blocks() {
+    let mut set = RelocationSet::new();
+
+    set.insert(0x2010, RelocationType::ImageRelBasedDir64);
+    set.insert(0x1008, RelocationType::ImageRelBasedHighLow);
+    set.insert(0x1004, RelocationType::ImageRelBasedHighLow);
+    set.insert_high_adj(0x300C, 0x8000);
+    set.insert(0x2000, RelocationType::ImageRelBasedDir64);
+    set.insert(0x2FF8, RelocationType::ImageRelBasedDir64);
+    assert_eq!(set.remove(0x2000), Some(RelocationType::ImageRelBasedDir64));
+    assert_eq!(set.remove(0x2000), None);
+    assert_eq!(set.len(), 5);
+
+    let data = set.build();
+    assert_eq!(
+        data,
+        [
+            0x00, 0x10, 0x00, 0x00, 0x0C, 0x00, 0x00, 0x00, 0x04, 0x30, 0x08, 0x30, 0x00, 0x20,
+            0x00, 0x00, 0x0C, 0x00, 0x00, 0x00, 0x10, 0xA0, 0xF8, 0xAF, 0x00, 0x30, 0x00, 0x00,
+            0x0C, 0x00, 0x00, 0x00, 0x0C, 0x40, 0x00, 0x80,
+        ]
+        .to_vec()
+    );
+
+    let relocs: Vec<Relocation> = RelocationsIter::new(&data).map(|r| r.unwrap()).collect();
+    assert_eq!(RelocationSet::from_relocations(&relocs), Ok(set));
+
+    // The block's page plus the entry offset wraps past the end of the address space
+    let relocs: Vec<Relocation> = RelocationsIter::new(&[
+        0x00, 0xFF, 0xFF, 0xFF, 0x0C, 0x00, 0x00, 0x00, 0x00, 0x3F, 0x00, 0x00,
+    ])
+    .map(|r| r.unwrap())
+    .collect();
+    assert_eq!(
+        RelocationSet::from_relocations(&relocs),
+        Err(PeError::RvaNotMapped { rva: 0xFFFF_FF00 })
+    );
+
+    let mut set = RelocationSet::new();
+    set.insert(0x1000, RelocationType::ImageRelBasedHighLow);
+    let relocs = set.to_relocations();
+    assert_eq_hex!(relocs[0].size_of_block, 0x0C);
+    assert_eq!(
+        relocs[0].block[1].reloc_type,
+        RelocationType::ImageRelBasedAbsolute
+    );
+}
+
+#[test]
+fn reloc_set_write_in_place() {
+    let buf = read_test_pe32();
+    let mut set = {
+        let mut buf = buf.clone();
+        RelocationSet::from_pe(&PeHeader::try_new(&mut buf).unwrap()).unwrap()
+    };
+
+    assert_eq!(set.len(), 2);
+    assert_eq!(set.remove_range(0x1000, 0x1005), 1);
+    set.insert(0x2000, RelocationType::ImageRelBasedHighLow);
+    set.insert(0x2004, RelocationType::ImageRelBasedHighLow);
+
+    let mut out = set.write(&buf).unwrap();
+    assert_eq!(out.len(), buf.len());
+
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    assert_eq!(pe_hdr.sec_hdrs.len(), 3);
+    assert_eq_hex!(pe_hdr.sec_hdrs[2].virt_size.val(), 0x18);
+    assert_eq!(
+        pe_hdr
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Reloc),
+        Some((0x3000, 0x18))
+    );
+    assert_eq!(RelocationSet::from_pe(&pe_hdr).unwrap(), set);
+}
+
+#[test]
+fn reloc_set_write_data_after() {
+    // A string right after the table keeps it from using the rest of the section
+    let mut buf = read_test_pe32();
+    buf[0x60C..0x619].copy_from_slice(b"NTDLL.RtlFoo\0");
+    PeHeader::new(&mut buf).sec_hdrs[2].virt_size.set(0x19);
+
+    let mut set = RelocationSet::new();
+    set.insert(0x1000, RelocationType::ImageRelBasedHighLow);
+    set.insert(0x2000, RelocationType::ImageRelBasedHighLow);
+
+    let mut out = set.write(&buf).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 4);
+    assert_eq!(pe_hdr.read_c_string_at_rva(0x300C).unwrap(), "NTDLL.RtlFoo");
+    assert_eq!(
+        pe_hdr
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Reloc),
+        Some((0x4000, 0x18))
+    );
+    assert_eq!(RelocationSet::from_pe(&pe_hdr).unwrap(), set);
+}
+
+#[test]
+fn reloc_set_write_grow() {
+    let buf = read_test_pe32();
+    let mut set = RelocationSet::new();
+
+    for i in 0..300 {
+        set.insert(0x1000 + i * 4, RelocationType::ImageRelBasedHighLow);
+    }
+
+    let mut out = set.write(&buf).unwrap();
+    assert_eq_hex!(out.len(), 0xA00);
+
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    let opt_hdr = &pe_hdr.nt_hdr.opt_hdr;
+    assert_eq!(pe_hdr.sec_hdrs.len(), 3);
+    assert_eq_hex!(pe_hdr.sec_hdrs[2].size_of_raw_data.val(), 0x400);
+    assert_eq_hex!(pe_hdr.sec_hdrs[2].virt_size.val(), 0x260);
+    assert_eq_hex!(opt_hdr.size_of_image.val(), 0x4000);
+    assert_eq!(
+        opt_hdr.data_dirs.rva_and_size(DataDirType::Reloc),
+        Some((0x3000, 0x260))
+    );
+    assert_eq!(RelocationSet::from_pe(&pe_hdr).unwrap(), set);
+
+    // The header claims more raw data than the file holds
+    let mut buf = read_test_pe32();
+    let size_of_init_data = {
+        let mut pe_hdr = PeHeader::new(&mut buf);
+        pe_hdr.sec_hdrs[2].size_of_raw_data.set(0x600);
+        pe_hdr.nt_hdr.opt_hdr.size_of_init_data.val()
+    };
+
+    let mut out = set.write(&buf).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    assert_eq_hex!(pe_hdr.sec_hdrs[2].size_of_raw_data.val(), 0x400);
+    assert_eq_hex!(
+        pe_hdr.nt_hdr.opt_hdr.size_of_init_data.val(),
+        size_of_init_data
+    );
+    assert_eq!(RelocationSet::from_pe(&pe_hdr).unwrap(), set);
+}
+
+#[test]
+fn reloc_set_write_append() {
+    use crate::imports::{add_import, ImportEntry};
+
+    let buf = add_import(
+        &read_test_pe(),
+        "ADVAPI32.dll",
+        &[ImportEntry::ByOrdinal(1)],
+    )
+    .unwrap();
+    let mut set = RelocationSet::new();
+
+    for i in 0..300 {
+        set.insert(0x1000 + i * 8, RelocationType::ImageRelBasedDir64);
+    }
+
+    let mut out = set.write(&buf).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    let opt_hdr = &pe_hdr.nt_hdr.opt_hdr;
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 7);
+    assert_eq!(*pe_hdr.sec_hdrs[6].name.as_ref(), b".reloc2\0");
+    assert_eq!(
+        opt_hdr.data_dirs.rva_and_size(DataDirType::Reloc),
+        Some((0x7000, 0x260))
+    );
+    assert_eq_hex!(opt_hdr.size_of_image.val(), 0x8000);
+    assert_eq!(RelocationSet::from_pe(&pe_hdr).unwrap(), set);
+}
@@ -0,0 +1,1058 @@
+use crate::{
+    error::PeError,
+    nt_hdr::DataDirType,
+    pe::PeHeader,
+    sec_hdr::{IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ},
+    sections::{append_section, grow_section},
+    util::{align_up, ROCursor, RWCursor},
+};
+use alloc::collections::BTreeMap;
+use alloc::prelude::v1::*;
+#[allow(unused_imports)]
+use assert_hex::assert_eq_hex;
+use byteorder::LittleEndian;
+use zordon::prelude::*;
+
+pub const RESOURCE_DIR_SIZE: usize = 0x10;
+pub const RESOURCE_DIR_ENTRY_SIZE: usize = 0x8;
+pub const RESOURCE_DATA_ENTRY_SIZE: usize = 0x10;
+pub const RESOURCE_SEC_NAME: &[u8] = b".rsrc2";
+
+// High bits of a directory entry's name and offset fields
+pub const RESOURCE_NAME_IS_STRING: u32 = 0x8000_0000;
+pub const RESOURCE_DATA_IS_DIR: u32 = 0x8000_0000;
+
+pub const RT_CURSOR: u16 = 1;
+pub const RT_BITMAP: u16 = 2;
+pub const RT_ICON: u16 = 3;
+pub const RT_MENU: u16 = 4;
+pub const RT_DIALOG: u16 = 5;
+pub const RT_STRING: u16 = 6;
+pub const RT_FONTDIR: u16 = 7;
+pub const RT_FONT: u16 = 8;
+pub const RT_ACCELERATOR: u16 = 9;
+pub const RT_RCDATA: u16 = 10;
+pub const RT_MESSAGETABLE: u16 = 11;
+pub const RT_GROUP_CURSOR: u16 = 12;
+pub const RT_GROUP_ICON: u16 = 14;
+pub const RT_VERSION: u16 = 16;
+pub const RT_DLGINCLUDE: u16 = 17;
+pub const RT_PLUGPLAY: u16 = 19;
+pub const RT_VXD: u16 = 20;
+pub const RT_ANICURSOR: u16 = 21;
+pub const RT_ANIICON: u16 = 22;
+pub const RT_HTML: u16 = 23;
+pub const RT_MANIFEST: u16 = 24;
+
+// Type, name or language of a resource. Named entries sort before numbered ones, the same order
+// they have in a directory table
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResourceId {
+    Name(String),
+    Id(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResourceDirectory {
+    pub characteristics: u32,
+    pub time_data_stamp: u32,
+    pub major_ver: u16,
+    pub minor_ver: u16,
+    pub num_of_named_entries: u16,
+    pub num_of_id_entries: u16,
+}
+
+impl ResourceDirectory {
+    pub fn new(buf: &[u8]) -> Result<Self, PeError> {
+        let mut cur = ROCursor::new(buf);
+
+        Ok(Self {
+            characteristics: cur.read_u32::<LittleEndian>()?,
+            time_data_stamp: cur.read_u32::<LittleEndian>()?,
+            major_ver: cur.read_u16::<LittleEndian>()?,
+            minor_ver: cur.read_u16::<LittleEndian>()?,
+            num_of_named_entries: cur.read_u16::<LittleEndian>()?,
+            num_of_id_entries: cur.read_u16::<LittleEndian>()?,
+        })
+    }
+
+    pub fn write(&self, buf: &mut RWCursor) -> Result<(), PeError> {
+        buf.write_u32::<LittleEndian>(self.characteristics)?;
+        buf.write_u32::<LittleEndian>(self.time_data_stamp)?;
+        buf.write_u16::<LittleEndian>(self.major_ver)?;
+        buf.write_u16::<LittleEndian>(self.minor_ver)?;
+        buf.write_u16::<LittleEndian>(self.num_of_named_entries)?;
+        buf.write_u16::<LittleEndian>(self.num_of_id_entries)
+    }
+
+    pub fn num_of_entries(&self) -> usize {
+        self.num_of_named_entries as usize + self.num_of_id_entries as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResourceDirEntry {
+    pub name: u32,           // Offset of a string if the high bit is set, otherwise an ID
+    pub offset_to_data: u32, // Offset of a subdirectory if the high bit is set, else a data entry
+}
+
+impl ResourceDirEntry {
+    pub fn is_named(&self) -> bool {
+        self.name & RESOURCE_NAME_IS_STRING != 0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.offset_to_data & RESOURCE_DATA_IS_DIR != 0
+    }
+
+    pub fn name_offset(&self) -> usize {
+        (self.name & !RESOURCE_NAME_IS_STRING) as usize
+    }
+
+    pub fn data_offset(&self) -> usize {
+        (self.offset_to_data & !RESOURCE_DATA_IS_DIR) as usize
+    }
+
+    pub fn write(&self, buf: &mut RWCursor) -> Result<(), PeError> {
+        buf.write_u32::<LittleEndian>(self.name)?;
+        buf.write_u32::<LittleEndian>(self.offset_to_data)
+    }
+}
+
+// Entries following a directory table, num_of_entries of them
+pub struct ResourceDirEntryIter<'a> {
+    cur: ROCursor<'a>,
+    left: usize,
+    done: bool,
+}
+
+impl<'a> ResourceDirEntryIter<'a> {
+    pub fn new(buf: &'a [u8], num_of_entries: usize) -> Self {
+        Self {
+            cur: ROCursor::new(buf),
+            left: num_of_entries,
+            done: false,
+        }
+    }
+
+    fn read_next(&mut self) -> Result<ResourceDirEntry, PeError> {
+        Ok(ResourceDirEntry {
+            name: self.cur.read_u32::<LittleEndian>()?,
+            offset_to_data: self.cur.read_u32::<LittleEndian>()?,
+        })
+    }
+}
+
+impl<'a> Iterator for ResourceDirEntryIter<'a> {
+    type Item = Result<ResourceDirEntry, PeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.left == 0 {
+            return None;
+        }
+
+        let r = self.read_next();
+        self.left -= 1;
+        self.done = r.is_err();
+        Some(r)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResourceDataEntry {
+    pub data_rva: u32,
+    pub size: u32,
+    pub code_page: u32,
+    pub reserved: u32,
+}
+
+impl ResourceDataEntry {
+    pub fn new(buf: &[u8]) -> Result<Self, PeError> {
+        let mut cur = ROCursor::new(buf);
+
+        Ok(Self {
+            data_rva: cur.read_u32::<LittleEndian>()?,
+            size: cur.read_u32::<LittleEndian>()?,
+            code_page: cur.read_u32::<LittleEndian>()?,
+            reserved: cur.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    pub fn write(&self, buf: &mut RWCursor) -> Result<(), PeError> {
+        buf.write_u32::<LittleEndian>(self.data_rva)?;
+        buf.write_u32::<LittleEndian>(self.size)?;
+        buf.write_u32::<LittleEndian>(self.code_page)?;
+        buf.write_u32::<LittleEndian>(self.reserved)
+    }
+}
+
+// A leaf of the type -> name -> language tree
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resource {
+    pub type_id: ResourceId,
+    pub name: ResourceId,
+    pub lang: u16,
+    pub entry: ResourceDataEntry,
+    pub entry_rva: u32, // Where entry itself is, in the resource directory
+    pub data: Vec<u8>,
+}
+
+// Offsets in the tree are relative to the start of the resource directory
+fn tree_slice(rsrc: &[u8], offset: usize, needed: usize) -> Result<&[u8], PeError> {
+    match rsrc.get(offset..) {
+        Some(s) if s.len() >= needed => Ok(s),
+        _ => Err(PeError::Truncated { offset, needed }),
+    }
+}
+
+fn dir_entries(rsrc: &[u8], offset: usize) -> Result<Vec<ResourceDirEntry>, PeError> {
+    let dir = ResourceDirectory::new(tree_slice(rsrc, offset, RESOURCE_DIR_SIZE)?)?;
+    let entries_offset = offset + RESOURCE_DIR_SIZE;
+    let entries_size = dir.num_of_entries() * RESOURCE_DIR_ENTRY_SIZE;
+
+    ResourceDirEntryIter::new(
+        tree_slice(rsrc, entries_offset, entries_size)?,
+        dir.num_of_entries(),
+    )
+    .collect()
+}
+
+// Subdirectory an entry at offset's table points to, the first two levels hold nothing else
+fn subdir(entry: &ResourceDirEntry, offset: usize) -> Result<usize, PeError> {
+    if !entry.is_dir() {
+        return Err(PeError::BadResourceEntry { offset });
+    }
+
+    Ok(entry.data_offset())
+}
+
+fn resource_id(rsrc: &[u8], entry: &ResourceDirEntry) -> Result<ResourceId, PeError> {
+    if !entry.is_named() {
+        return Ok(ResourceId::Id(entry.name as u16));
+    }
+
+    // Counted UTF-16 string, not null terminated
+    let mut cur = ROCursor::new(rsrc);
+    cur.seek(entry.name_offset())?;
+    let len = cur.read_u16::<LittleEndian>()? as usize;
+    let mut units = Vec::with_capacity(len);
+
+    for _ in 0..len {
+        units.push(cur.read_u16::<LittleEndian>()?);
+    }
+
+    Ok(ResourceId::Name(String::from_utf16_lossy(&units)))
+}
+
+impl<'a> PeHeader<'a> {
+    pub fn resource_directory(&self) -> Result<Option<ResourceDirectory>, PeError> {
+        match self
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Resource)
+        {
+            Some((rva, _)) => Ok(Some(ResourceDirectory::new(&self.data_at_rva(rva)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Every resource in the tree in table order, empty if there is no resource directory
+    pub fn resources(&self) -> Result<Vec<Resource>, PeError> {
+        let (rsrc_rva, _) = match self
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Resource)
+        {
+            Some(d) => d,
+            None => return Ok(Vec::new()),
+        };
+
+        let rsrc = self.data_at_rva(rsrc_rva)?;
+        let mut resources = Vec::new();
+
+        for type_entry in dir_entries(&rsrc, 0)? {
+            let type_id = resource_id(&rsrc, &type_entry)?;
+            let names_offset = subdir(&type_entry, 0)?;
+
+            for name_entry in dir_entries(&rsrc, names_offset)? {
+                let name = resource_id(&rsrc, &name_entry)?;
+                let langs_offset = subdir(&name_entry, names_offset)?;
+
+                for lang_entry in dir_entries(&rsrc, langs_offset)? {
+                    if lang_entry.is_named() || lang_entry.is_dir() {
+                        return Err(PeError::BadResourceEntry {
+                            offset: langs_offset,
+                        });
+                    }
+
+                    let entry = ResourceDataEntry::new(tree_slice(
+                        &rsrc,
+                        lang_entry.data_offset(),
+                        RESOURCE_DATA_ENTRY_SIZE,
+                    )?)?;
+
+                    resources.push(Resource {
+                        type_id: type_id.clone(),
+                        name: name.clone(),
+                        lang: lang_entry.name as u16,
+                        data: self
+                            .read_at_rva(entry.data_rva, entry.size as usize)?
+                            .to_vec(),
+                        entry,
+                        entry_rva: rsrc_rva + lang_entry.data_offset() as u32,
+                    });
+                }
+            }
+        }
+
+        Ok(resources)
+    }
+
+    // First resource of the type, in any language. Any name matches when name is None
+    pub fn find_resource(
+        &self,
+        type_id: &ResourceId,
+        name: Option<&ResourceId>,
+    ) -> Result<Option<Resource>, PeError> {
+        Ok(self
+            .resources()?
+            .into_iter()
+            .find(|r| r.type_id == *type_id && (name.is_none() || name == Some(&r.name))))
+    }
+}
+
+// Swaps the data of a single resource, leaving the tree as it is. Data that fits over the old
+// data is written in place, anything bigger goes in a new section the data entry is pointed at
+pub fn replace_resource_data(
+    buf: &[u8],
+    resource: &Resource,
+    data: &[u8],
+) -> Result<Vec<u8>, PeError> {
+    let mut out = buf.to_vec();
+    let old_rva = resource.entry.data_rva;
+    let old_size = resource.entry.size as usize;
+
+    let data_rva = if data.len() <= old_size {
+        let pe_hdr = PeHeader::try_new(&mut out)?;
+        let offset = pe_hdr.rva_to_offset(old_rva)?;
+        let mut dst = pe_hdr.data_at_rva_mut(old_rva)?;
+
+        // The entry's size can claim more than the section holds
+        let dst = dst.get_mut(..old_size).ok_or(PeError::Truncated {
+            offset,
+            needed: old_size,
+        })?;
+
+        dst[..data.len()].copy_from_slice(data);
+        dst[data.len()..].iter_mut().for_each(|b| *b = 0);
+        old_rva
+    } else {
+        let sec_virt_addr = PeHeader::try_new(&mut out)?.next_sec_virt_addr()?;
+
+        out = append_section(
+            &out,
+            RESOURCE_SEC_NAME,
+            IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+            data,
+        )?;
+        sec_virt_addr
+    };
+
+    {
+        let pe_hdr = PeHeader::try_new(&mut out)?;
+        let mut entry_buf = pe_hdr.data_at_rva_mut(resource.entry_rva)?;
+
+        ResourceDataEntry {
+            data_rva,
+            size: data.len() as u32,
+            ..resource.entry
+        }
+        .write(&mut RWCursor::new(&mut entry_buf))?;
+    }
+
+    Ok(out)
+}
+
+// (type, name, language)
+pub type ResourceKey = (ResourceId, ResourceId, u16);
+
+// Regenerates the whole resource directory. The header fields are used for every directory table
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ResourceBuilder {
+    pub characteristics: u32,
+    pub time_data_stamp: u32,
+    pub major_ver: u16,
+    pub minor_ver: u16,
+    entries: BTreeMap<ResourceKey, (Vec<u8>, u32)>, // Data and code page
+}
+
+impl ResourceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_pe(pe_hdr: &PeHeader) -> Result<Self, PeError> {
+        let dir = pe_hdr
+            .resource_directory()?
+            .ok_or(PeError::DataDirNotPresent {
+                dir: DataDirType::Resource,
+            })?;
+
+        let mut builder = Self {
+            characteristics: dir.characteristics,
+            time_data_stamp: dir.time_data_stamp,
+            major_ver: dir.major_ver,
+            minor_ver: dir.minor_ver,
+            entries: BTreeMap::new(),
+        };
+
+        for r in pe_hdr.resources()? {
+            builder
+                .entries
+                .insert((r.type_id, r.name, r.lang), (r.data, r.entry.code_page));
+        }
+
+        Ok(builder)
+    }
+
+    // Adds a resource or replaces the data of an existing one, returning the old data
+    pub fn insert(
+        &mut self,
+        type_id: ResourceId,
+        name: ResourceId,
+        lang: u16,
+        data: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        self.entries
+            .insert((type_id, name, lang), (data, 0))
+            .map(|(old, _)| old)
+    }
+
+    // Removes one language of a resource, or every language when lang is None
+    pub fn remove(
+        &mut self,
+        type_id: &ResourceId,
+        name: &ResourceId,
+        lang: Option<u16>,
+    ) -> Result<(), PeError> {
+        let len = self.entries.len();
+
+        self.entries.retain(|(t, n, l), _| {
+            !(t == type_id && n == name && (lang.is_none() || lang == Some(*l)))
+        });
+
+        if self.entries.len() == len {
+            return Err(PeError::ResourceNotFound);
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, type_id: &ResourceId, name: &ResourceId, lang: u16) -> Option<&[u8]> {
+        self.entries
+            .get(&(type_id.clone(), name.clone(), lang))
+            .map(|(data, _)| data.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ResourceKey, &[u8])> {
+        self.entries
+            .iter()
+            .map(|(k, (data, _))| (k, data.as_slice()))
+    }
+
+    fn table(&self, ids: &[&ResourceId], num_of_entries: usize) -> ResourceDirectory {
+        let named = ids
+            .iter()
+            .filter(|id| matches!(id, ResourceId::Name(_)))
+            .count();
+
+        ResourceDirectory {
+            characteristics: self.characteristics,
+            time_data_stamp: self.time_data_stamp,
+            major_ver: self.major_ver,
+            minor_ver: self.minor_ver,
+            num_of_named_entries: named as u16,
+            num_of_id_entries: (num_of_entries - named) as u16,
+        }
+    }
+
+    // Laid out as the directory tables level by level, then the name strings, the data entries
+    // and finally the data, each aligned to 8 bytes. virt_addr is where the start will be mapped
+    pub fn build(&self, virt_addr: u32) -> Result<Vec<u8>, PeError> {
+        // Each type with its names, each name with its languages
+        type Names<'a> = Vec<(&'a ResourceId, Vec<u16>)>;
+        let mut types: Vec<(&ResourceId, Names)> = Vec::new();
+
+        for (type_id, name, lang) in self.entries.keys() {
+            if types.last().map(|t| t.0) != Some(type_id) {
+                types.push((type_id, Vec::new()));
+            }
+
+            let names = &mut types.last_mut().unwrap().1;
+
+            if names.last().map(|n| n.0) != Some(name) {
+                names.push((name, Vec::new()));
+            }
+
+            names.last_mut().unwrap().1.push(*lang);
+        }
+
+        let table_size = |n: usize| RESOURCE_DIR_SIZE + n * RESOURCE_DIR_ENTRY_SIZE;
+        let mut offset = table_size(types.len());
+        let mut name_tables = Vec::new();
+        let mut lang_tables = Vec::new();
+
+        for (_, names) in types.iter() {
+            name_tables.push(offset);
+            offset += table_size(names.len());
+        }
+
+        for (_, langs) in types.iter().flat_map(|(_, names)| names.iter()) {
+            lang_tables.push(offset);
+            offset += table_size(langs.len());
+        }
+
+        let mut strings: Vec<(&str, usize)> = Vec::new();
+
+        for (type_id, names) in types.iter() {
+            for id in core::iter::once(*type_id).chain(names.iter().map(|n| n.0)) {
+                if let ResourceId::Name(s) = id {
+                    if !strings.iter().any(|(k, _)| k == s) {
+                        strings.push((s, offset));
+                        offset += 2 + s.encode_utf16().count() * 2;
+                    }
+                }
+            }
+        }
+
+        let data_entries = align_up(offset as u32, 4)? as usize;
+        offset = data_entries + self.entries.len() * RESOURCE_DATA_ENTRY_SIZE;
+
+        let data_offsets: Vec<usize> = self
+            .entries
+            .values()
+            .map(|(data, _)| {
+                let start = align_up(offset as u32, 8)? as usize;
+                offset = start + data.len();
+                Ok(start)
+            })
+            .collect::<Result<_, PeError>>()?;
+
+        let name_field = |id: &ResourceId| match id {
+            ResourceId::Name(s) => {
+                let (_, offset) = strings.iter().find(|(k, _)| k == s).unwrap();
+                *offset as u32 | RESOURCE_NAME_IS_STRING
+            }
+            ResourceId::Id(id) => *id as u32,
+        };
+
+        // Sized from the layout above so none of the writes can run out of room
+        let mut out = vec![0; offset];
+        let mut cur = RWCursor::new(&mut out);
+        let type_ids: Vec<&ResourceId> = types.iter().map(|t| t.0).collect();
+
+        self.table(&type_ids, type_ids.len())
+            .write(&mut cur)
+            .unwrap();
+
+        for (type_id, table) in type_ids.iter().zip(name_tables.iter()) {
+            ResourceDirEntry {
+                name: name_field(type_id),
+                offset_to_data: *table as u32 | RESOURCE_DATA_IS_DIR,
+            }
+            .write(&mut cur)
+            .unwrap();
+        }
+
+        let mut lang_tables = lang_tables.iter();
+        let mut data_entry = data_entries;
+
+        for ((_, names), table) in types.iter().zip(name_tables.iter()) {
+            let name_ids: Vec<&ResourceId> = names.iter().map(|n| n.0).collect();
+            cur.seek(*table).unwrap();
+            self.table(&name_ids, name_ids.len())
+                .write(&mut cur)
+                .unwrap();
+
+            for (name, langs) in names.iter() {
+                let lang_table = *lang_tables.next().unwrap();
+
+                ResourceDirEntry {
+                    name: name_field(name),
+                    offset_to_data: lang_table as u32 | RESOURCE_DATA_IS_DIR,
+                }
+                .write(&mut cur)
+                .unwrap();
+
+                // Language tables only ever hold IDs
+                let resume = cur.position();
+                cur.seek(lang_table).unwrap();
+                self.table(&[], langs.len()).write(&mut cur).unwrap();
+
+                for lang in langs.iter() {
+                    ResourceDirEntry {
+                        name: *lang as u32,
+                        offset_to_data: data_entry as u32,
+                    }
+                    .write(&mut cur)
+                    .unwrap();
+                    data_entry += RESOURCE_DATA_ENTRY_SIZE;
+                }
+
+                cur.seek(resume).unwrap();
+            }
+        }
+
+        for (s, offset) in strings.iter() {
+            cur.seek(*offset).unwrap();
+            cur.write_u16::<LittleEndian>(s.encode_utf16().count() as u16)
+                .unwrap();
+
+            for u in s.encode_utf16() {
+                cur.write_u16::<LittleEndian>(u).unwrap();
+            }
+        }
+
+        cur.seek(data_entries).unwrap();
+
+        for ((data, code_page), data_offset) in self.entries.values().zip(data_offsets.iter()) {
+            ResourceDataEntry {
+                data_rva: virt_addr + *data_offset as u32,
+                size: data.len() as u32,
+                code_page: *code_page,
+                reserved: 0,
+            }
+            .write(&mut cur)
+            .unwrap();
+        }
+
+        for ((data, _), data_offset) in self.entries.values().zip(data_offsets.iter()) {
+            out[*data_offset..*data_offset + data.len()].copy_from_slice(data);
+        }
+
+        Ok(out)
+    }
+
+    // Writes the directory over the existing one when it fits in the old size. When the old one is
+    // the last thing in its section it can also use the rest of the raw data or grow the section,
+    // otherwise a new section is appended
+    pub fn write(&self, buf: &[u8]) -> Result<Vec<u8>, PeError> {
+        let mut out = buf.to_vec();
+
+        let old = {
+            let pe_hdr = PeHeader::try_new(&mut out)?;
+            let data_dirs = &pe_hdr.nt_hdr.opt_hdr.data_dirs;
+
+            if data_dirs.resource.is_none() {
+                return Err(PeError::DataDirNotPresent {
+                    dir: DataDirType::Resource,
+                });
+            }
+
+            data_dirs
+                .rva_and_size(DataDirType::Resource)
+                .map(|(rva, size)| {
+                    // Past the section's virtual size there is only padding, so a directory
+                    // reaching it has nothing after it to overwrite
+                    let last = pe_hdr.virt_addr_to_sec_index(rva).ok().filter(|i| {
+                        let s = &pe_hdr.sec_hdrs[*i];
+                        rva as u64 + size as u64
+                            >= s.virt_addr.val() as u64 + s.virt_size.val() as u64
+                    });
+
+                    (
+                        rva,
+                        size as usize,
+                        pe_hdr.data_at_rva(rva).map(|d| d.len()).unwrap_or(0),
+                        last,
+                    )
+                })
+        };
+
+        let (rva, data) = match old {
+            Some((rva, size, room, last)) => {
+                let data = self.build(rva)?;
+
+                match last {
+                    _ if data.len() <= size || (last.is_some() && data.len() <= room) => {
+                        write_in_place(&mut out, rva, &data, size)?;
+                        (rva, data)
+                    }
+                    Some(i) => {
+                        let sec_offset =
+                            rva - PeHeader::try_new(&mut out)?.sec_hdrs[i].virt_addr.val();
+
+                        match grow_section(&out, i, sec_offset + data.len() as u32) {
+                            Ok(grown) => {
+                                out = grown;
+                                write_in_place(&mut out, rva, &data, size)?;
+                                (rva, data)
+                            }
+                            Err(PeError::SectionWouldOverlap { .. }) => self.append(&mut out)?,
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    None => self.append(&mut out)?,
+                }
+            }
+            None => self.append(&mut out)?,
+        };
+
+        {
+            let mut pe_hdr = PeHeader::try_new(&mut out)?;
+
+            if let Some(rsrc_dir) = pe_hdr.nt_hdr.opt_hdr.data_dirs.resource.as_mut() {
+                rsrc_dir.virt_addr.set(rva);
+                rsrc_dir.size.set(data.len() as u32);
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn append(&self, out: &mut Vec<u8>) -> Result<(u32, Vec<u8>), PeError> {
+        let rva = PeHeader::try_new(out)?.next_sec_virt_addr()?;
+        let data = self.build(rva)?;
+
+        *out = append_section(
+            out,
+            RESOURCE_SEC_NAME,
+            IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+            &data,
+        )?;
+
+        Ok((rva, data))
+    }
+}
+
+// Overwrites old_size bytes at rva, zeroing whatever data doesn't cover. The section's virtual
+// size is bumped if data runs past it
+fn write_in_place(out: &mut [u8], rva: u32, data: &[u8], old_size: usize) -> Result<(), PeError> {
+    let mut pe_hdr = PeHeader::try_new(out)?;
+
+    {
+        let mut dst = pe_hdr.data_at_rva_mut(rva)?;
+        let old_end = old_size.min(dst.len()).max(data.len());
+
+        dst[..data.len()].copy_from_slice(data);
+        dst[data.len()..old_end].iter_mut().for_each(|b| *b = 0);
+    }
+
+    let i = pe_hdr.virt_addr_to_sec_index(rva)?;
+    let s = &mut pe_hdr.sec_hdrs[i];
+    let end = rva - s.virt_addr.val() + data.len() as u32;
+
+    if end > s.virt_size.val() {
+        s.virt_size.set(end);
+    }
+
+    Ok(())
+}
+
+//Tests
+#[cfg(feature = "std_unit_tests")]
+#[cfg(test)]
+use crate::pe::{read_test_pe, read_test_pe64};
+
+#[test]
+fn resource_directory() {
+    let mut buf = read_test_pe64();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let rsrc = pe_hdr.data_at_rva(0x4000).unwrap();
+
+    let dir = ResourceDirectory::new(&rsrc).unwrap();
+    assert_eq_hex!(dir.major_ver, 4);
+    assert_eq_hex!(dir.num_of_named_entries, 1);
+    assert_eq_hex!(dir.num_of_id_entries, 2);
+
+    let entries: Vec<ResourceDirEntry> = ResourceDirEntryIter::new(&rsrc[0x10..], 3)
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert!(entries[0].is_named() && entries[0].is_dir());
+    assert_eq_hex!(entries[0].name_offset(), 0xC0);
+    assert_eq_hex!(entries[0].data_offset(), 0x28);
+    assert_eq_hex!(entries[2].name, RT_MANIFEST as u32);
+    assert_eq_hex!(entries[2].data_offset(), 0x58);
+}
+
+#[test]
+fn resources() {
+    let mut buf = read_test_pe64();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let resources = pe_hdr.resources().unwrap();
+
+    assert_eq!(resources.len(), 4);
+
+    let mydata = ResourceId::Name("MYDATA".to_string());
+    let settings = ResourceId::Name("SETTINGS".to_string());
+    assert_eq!(resources[0].type_id, mydata);
+    assert_eq!(resources[0].name, settings);
+    assert_eq_hex!(resources[0].lang, 0x407);
+    assert_eq!(resources[0].data, b"schluessel=wert\n");
+    assert_eq_hex!(resources[1].lang, 0x409);
+    assert_eq!(resources[1].data, b"key=value\n");
+    assert_eq!(
+        resources[1].entry,
+        ResourceDataEntry {
+            data_rva: 0x4130,
+            size: 0xA,
+            code_page: 0,
+            reserved: 0
+        }
+    );
+    assert_eq_hex!(resources[1].entry_rva, 0x40F0);
+
+    assert_eq!(resources[2].type_id, ResourceId::Id(RT_VERSION));
+    assert_eq!(resources[2].name, ResourceId::Id(1));
+    assert_eq_hex!(resources[2].data.len(), 0x1E4);
+    assert_eq!(resources[3].type_id, ResourceId::Id(RT_MANIFEST));
+    assert_eq!(resources[3].data[..5], *b"<?xml");
+
+    let manifest = pe_hdr
+        .find_resource(&ResourceId::Id(RT_MANIFEST), None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(manifest, resources[3]);
+    assert_eq!(
+        pe_hdr
+            .find_resource(&mydata, Some(&ResourceId::Id(1)))
+            .unwrap(),
+        None
+    );
+
+    // No resource directory
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::new(&mut buf);
+    assert!(pe_hdr.resources().unwrap().is_empty());
+}
+
+#[test]
+fn resources_bad_tree() {
+    // RT_VERSION type entry pointing straight at a data entry
+    let mut buf = read_test_pe64();
+    buf[0xA00 + 0x1F] = 0;
+    let pe_hdr = PeHeader::new(&mut buf);
+    assert_eq!(
+        pe_hdr.resources(),
+        Err(PeError::BadResourceEntry { offset: 0 })
+    );
+
+    // MYDATA name string past the end of the section
+    let mut buf = read_test_pe64();
+    buf[0xA00 + 0x11] = 0x10;
+    let pe_hdr = PeHeader::new(&mut buf);
+    assert!(matches!(pe_hdr.resources(), Err(PeError::Truncated { .. })));
+}
+
+#[test]
+fn resource_data_replace() {
+    let buf = read_test_pe64();
+    let resources = PeHeader::new(&mut buf.clone()).resources().unwrap();
+
+    // Fits over the old data
+    let mut out = replace_resource_data(&buf, &resources[1], b"k=v").unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+    let replaced = &pe_hdr.resources().unwrap()[1];
+
+    assert_eq_hex!(replaced.entry.data_rva, 0x4130);
+    assert_eq_hex!(replaced.entry.size, 3);
+    assert_eq!(replaced.data, b"k=v");
+    assert_eq_hex!(
+        *pe_hdr.read_at_rva(0x4130, 0xA).unwrap(),
+        *b"k=v\0\0\0\0\0\0\0"
+    );
+
+    // Too big, goes in a new section
+    let data = vec![0xAB; 0x300];
+    let mut out = replace_resource_data(&buf, &resources[1], &data).unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+    let replaced = &pe_hdr.resources().unwrap()[1];
+
+    assert_eq!(*pe_hdr.sec_hdrs[4].name.as_ref(), b".rsrc2\0\0");
+    assert_eq_hex!(replaced.entry.data_rva, 0x5000);
+    assert_eq!(replaced.data, data);
+    assert_eq!(pe_hdr.resources().unwrap()[0], resources[0]);
+
+    // The entry claims more data than is left in the section
+    let mut resource = resources[1].clone();
+    resource.entry.size = 0x1000;
+    assert_eq!(
+        replace_resource_data(&buf, &resource, b"k=v"),
+        Err(PeError::Truncated {
+            offset: 0xB30,
+            needed: 0x1000
+        })
+    );
+}
+
+#[test]
+fn resource_builder_build() {
+    let mut buf = read_test_pe64();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let mut builder = ResourceBuilder::from_pe(&pe_hdr).unwrap();
+    let mydata = ResourceId::Name("MYDATA".to_string());
+    let settings = ResourceId::Name("SETTINGS".to_string());
+
+    assert_eq!(builder.len(), 4);
+    assert_eq_hex!(builder.major_ver, 4);
+    assert_eq!(
+        builder.get(&mydata, &settings, 0x409),
+        Some(&b"key=value\n"[..])
+    );
+
+    // Unchanged it comes out the same as the original
+    assert_eq_hex!(
+        builder.build(0x4000).unwrap(),
+        pe_hdr.read_at_rva(0x4000, 0x67C).unwrap().to_vec()
+    );
+
+    assert_eq!(
+        builder.insert(mydata.clone(), settings.clone(), 0x409, b"k=v".to_vec()),
+        Some(b"key=value\n".to_vec())
+    );
+    assert_eq!(
+        builder.insert(
+            ResourceId::Id(RT_RCDATA),
+            ResourceId::Id(7),
+            0,
+            b"seven".to_vec()
+        ),
+        None
+    );
+    assert_eq!(builder.len(), 5);
+
+    assert_eq!(builder.remove(&mydata, &settings, None), Ok(()));
+    assert_eq!(
+        builder.remove(&mydata, &settings, None),
+        Err(PeError::ResourceNotFound)
+    );
+    assert_eq!(
+        builder.remove(&ResourceId::Id(RT_VERSION), &ResourceId::Id(1), Some(0x407)),
+        Err(PeError::ResourceNotFound)
+    );
+    assert_eq!(builder.len(), 3);
+
+    assert_eq!(
+        builder.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(),
+        vec![
+            (ResourceId::Id(RT_RCDATA), ResourceId::Id(7), 0),
+            (ResourceId::Id(RT_VERSION), ResourceId::Id(1), 0x409),
+            (ResourceId::Id(RT_MANIFEST), ResourceId::Id(2), 0x409),
+        ]
+    );
+}
+
+#[test]
+fn resource_builder_write() {
+    let buf = read_test_pe64();
+    let resources = PeHeader::new(&mut buf.clone()).resources().unwrap();
+    let mut builder = ResourceBuilder::from_pe(&PeHeader::new(&mut buf.clone())).unwrap();
+    let mydata = ResourceId::Name("MYDATA".to_string());
+    let settings = ResourceId::Name("SETTINGS".to_string());
+
+    // Smaller, written over the old directory
+    builder.remove(&mydata, &settings, Some(0x407)).unwrap();
+    let mut out = builder.write(&buf).unwrap();
+    assert_eq!(out.len(), buf.len());
+
+    let pe_hdr = PeHeader::new(&mut out);
+    let written = pe_hdr.resources().unwrap();
+
+    assert_eq!(written.len(), 3);
+    assert_eq!(written[0].data, resources[1].data);
+    assert_eq!(written[2].data, resources[3].data);
+    assert_eq!(
+        pe_hdr
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Resource),
+        Some((0x4000, builder.build(0x4000).unwrap().len() as u32))
+    );
+
+    // .rsrc is the last section, so it grows
+    let big = vec![0x5A; 0xA00];
+    builder.insert(ResourceId::Id(RT_RCDATA), ResourceId::Id(1), 0, big.clone());
+    let mut out = builder.write(&buf).unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+    let written = pe_hdr.resources().unwrap();
+    let size = builder.build(0x4000).unwrap().len() as u32;
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 4);
+    assert_eq_hex!(pe_hdr.sec_hdrs[3].virt_size.val(), size);
+    assert!(size > 0x1000);
+    assert_eq_hex!(
+        pe_hdr.sec_hdrs[3].size_of_raw_data.val(),
+        align_up(size, 0x200).unwrap()
+    );
+    assert_eq_hex!(pe_hdr.nt_hdr.opt_hdr.size_of_image.val(), 0x6000);
+    assert_eq!(written.len(), 4);
+    assert_eq!(written[1].type_id, ResourceId::Id(RT_RCDATA));
+    assert_eq!(written[1].data, big);
+
+    // Something else follows a directory that doesn't reach the end of .rsrc, so a bigger one
+    // can't be written over it
+    let mut shared = buf.clone();
+    PeHeader::new(&mut shared)
+        .nt_hdr
+        .opt_hdr
+        .data_dirs
+        .resource
+        .as_mut()
+        .unwrap()
+        .size
+        .set(0x300);
+    builder
+        .remove(&ResourceId::Id(RT_RCDATA), &ResourceId::Id(1), None)
+        .unwrap();
+    let mut out = builder.write(&shared).unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 5);
+    assert_eq!(*pe_hdr.sec(3).unwrap(), shared[0xA00..0x1200]);
+    assert_eq!(
+        pe_hdr
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Resource),
+        Some((0x5000, builder.build(0x5000).unwrap().len() as u32))
+    );
+    assert_eq!(pe_hdr.resources().unwrap().len(), 3);
+
+    // No resources to start with, a section is added for them
+    let buf = read_test_pe();
+    let mut builder = ResourceBuilder::new();
+    builder.insert(
+        mydata.clone(),
+        settings.clone(),
+        0x409,
+        b"key=value\n".to_vec(),
+    );
+    let mut out = builder.write(&buf).unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+    let written = pe_hdr.resources().unwrap();
+
+    assert_eq!(*pe_hdr.sec_hdrs[5].name.as_ref(), b".rsrc2\0\0");
+    assert_eq!(
+        pe_hdr
+            .nt_hdr
+            .opt_hdr
+            .data_dirs
+            .rva_and_size(DataDirType::Resource),
+        Some((0x6000, builder.build(0x6000).unwrap().len() as u32))
+    );
+    assert_eq!(written.len(), 1);
+    assert_eq!(written[0].name, settings);
+    assert_eq!(written[0].data, b"key=value\n");
+}
@@ -1,6 +1,16 @@
 use zordon::types::*;
 use zordon::MutView;
 
+pub const SEC_HDR_SIZE: usize = 0x28;
+
+pub const IMAGE_SCN_CNT_CODE: u32 = 0x0000_0020;
+pub const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x0000_0040;
+pub const IMAGE_SCN_CNT_UNINITIALIZED_DATA: u32 = 0x0000_0080;
+pub const IMAGE_SCN_MEM_DISCARDABLE: u32 = 0x0200_0000;
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+pub const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+pub const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
 #[derive(MutView, Debug, PartialEq)]
 pub struct SectionHeader<'a> {
     pub name: ArrayView<'a, [u8; 0x08]>,
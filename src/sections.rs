@@ -0,0 +1,830 @@
+use crate::{
+    error::PeError,
+    nt_hdr::{DataDirType, IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE, IMAGE_FILE_RELOCS_STRIPPED},
+    pe::PeHeader,
+    sec_hdr::*,
+    util::align_up,
+};
+use alloc::prelude::v1::*;
+#[allow(unused_imports)]
+use assert_hex::assert_eq_hex;
+use byteorder::{ByteOrder, LittleEndian};
+use zordon::prelude::*;
+
+pub const DEBUG_DIR_ENTRY_SIZE: usize = 0x1C;
+
+// Data directories that are dropped along with the section they point into. Any other directory
+// means the section is still in use
+const STRIPPABLE_DIRS: [DataDirType; 2] = [DataDirType::Reloc, DataDirType::Debug];
+
+impl<'a> PeHeader<'a> {
+    // Virtual address a section appended after all the existing ones would be placed at, an
+    // error if that is past the end of the address space
+    pub fn next_sec_virt_addr(&self) -> Result<u32, PeError> {
+        let mut end = None;
+
+        for s in self.sec_hdrs.iter() {
+            let sec_end = s
+                .virt_addr
+                .val()
+                .checked_add(self.sec_virt_size(s)?)
+                .ok_or(PeError::AddressOverflow)?;
+            end = end.max(Some(sec_end));
+        }
+
+        let end = end.unwrap_or_else(|| self.nt_hdr.opt_hdr.size_of_hdrs.val());
+
+        align_up(end, self.nt_hdr.opt_hdr.sec_alignment.val())
+    }
+}
+
+// Appends a section after the last one, both virtually and on disk. The raw data goes at the
+// end of the file, after any overlay. If the headers are full they are grown and everything
+// after them is moved down, see grow_headers
+pub fn append_section(
+    buf: &[u8],
+    name: &[u8],
+    characteristics: u32,
+    data: &[u8],
+) -> Result<Vec<u8>, PeError> {
+    if name.len() > 8 {
+        return Err(PeError::SectionNameTooLong { len: name.len() });
+    }
+
+    let mut out = grow_headers(buf, SEC_HDR_SIZE)?;
+    let buf_len = out.len();
+    let virt_size = data.len() as u32;
+
+    let (sec_hdr_offset, virt_addr, ptr_to_raw_data, size_of_raw_data) = {
+        let pe_hdr = PeHeader::try_new(&mut out)?;
+        let opt_hdr = &pe_hdr.nt_hdr.opt_hdr;
+        let sec_hdr_offset = pe_hdr.body_offset;
+
+        let file_alignment = opt_hdr.file_alignment.val();
+        let size_of_raw_data = align_up(virt_size, file_alignment)?;
+        let ptr_to_raw_data = match size_of_raw_data {
+            0 => 0,
+            _ => align_up(buf_len as u32, file_alignment)?,
+        };
+
+        (
+            sec_hdr_offset,
+            pe_hdr.next_sec_virt_addr()?,
+            ptr_to_raw_data,
+            size_of_raw_data,
+        )
+    };
+
+    let virt_end = virt_addr
+        .checked_add(virt_size)
+        .ok_or(PeError::AddressOverflow)?;
+
+    if size_of_raw_data != 0 {
+        let ptr_to_raw_data = ptr_to_raw_data as usize;
+        out.resize(ptr_to_raw_data + size_of_raw_data as usize, 0);
+        out[ptr_to_raw_data..ptr_to_raw_data + data.len()].copy_from_slice(data);
+    }
+
+    {
+        let mut pe_hdr = PeHeader::try_new(&mut out)?;
+        let opt_hdr = &mut pe_hdr.nt_hdr.opt_hdr;
+        let sec_alignment = opt_hdr.sec_alignment.val();
+
+        pe_hdr.nt_hdr.file_hdr.num_of_secs += 1;
+        opt_hdr
+            .size_of_image
+            .set(align_up(virt_end, sec_alignment)?);
+
+        if characteristics & IMAGE_SCN_CNT_CODE != 0 {
+            opt_hdr.size_of_code += size_of_raw_data;
+        }
+
+        if characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0 {
+            opt_hdr.size_of_init_data += size_of_raw_data;
+        }
+
+        if characteristics & IMAGE_SCN_CNT_UNINITIALIZED_DATA != 0 {
+            opt_hdr.size_of_uninit_data += align_up(virt_size, opt_hdr.file_alignment.val())?;
+        }
+    }
+
+    let mut padded_name = [0u8; 8];
+    padded_name[..name.len()].copy_from_slice(name);
+
+    let (mut sec_hdr, _) = SectionHeader::mut_view(&mut out[sec_hdr_offset..]);
+    sec_hdr.name.set(&padded_name);
+    sec_hdr.virt_size.set(virt_size);
+    sec_hdr.virt_addr.set(virt_addr);
+    sec_hdr.size_of_raw_data.set(size_of_raw_data);
+    sec_hdr.ptr_to_raw_data.set(ptr_to_raw_data);
+    sec_hdr.ptr_to_relocs.set(0);
+    sec_hdr.ptr_to_line_nums.set(0);
+    sec_hdr.num_of_relocs.set(0);
+    sec_hdr.num_of_line_nums.set(0);
+    sec_hdr.characteristics.set(characteristics);
+
+    Ok(out)
+}
+
+// Grows section index to at least size bytes, both virtually and on disk. Raw data after the
+// section is moved down by a multiple of file_alignment to make room. The section can't grow
+// into the virtual range of the section after it
+pub fn grow_section(buf: &[u8], index: usize, size: u32) -> Result<Vec<u8>, PeError> {
+    let mut out = buf.to_vec();
+
+    let (raw_end, shift, characteristics) = {
+        let pe_hdr = PeHeader::try_new(&mut out)?;
+        let opt_hdr = &pe_hdr.nt_hdr.opt_hdr;
+        let s = pe_hdr
+            .sec_hdrs
+            .get(index)
+            .ok_or(PeError::BadSectionIndex { index })?;
+        let virt_addr = s.virt_addr.val();
+        let characteristics = s.characteristics.val();
+
+        let next_virt_addr = pe_hdr
+            .sec_hdrs
+            .iter()
+            .map(|s| s.virt_addr.val())
+            .filter(|va| *va > virt_addr)
+            .min();
+        let virt_end = virt_addr as u64 + size.max(s.size_of_raw_data.val()) as u64;
+
+        if let Some(next) = next_virt_addr {
+            if virt_end > next as u64 {
+                return Err(PeError::SectionWouldOverlap { index });
+            }
+        }
+
+        // Uninitialised data only needs the bigger virtual size
+        let raw = if characteristics & IMAGE_SCN_CNT_UNINITIALIZED_DATA != 0 {
+            None
+        } else {
+            match &pe_hdr.sec_ranges[index] {
+                Some(r) => Some(r.clone()),
+                None => Some(buf.len()..buf.len()),
+            }
+        };
+
+        match raw {
+            Some(r) if (r.len() as u32) < size => {
+                let file_alignment = opt_hdr.file_alignment.val();
+                let shift = align_up(size - r.len() as u32, file_alignment)?;
+                (Some(r), shift, characteristics)
+            }
+            _ => (None, 0, characteristics),
+        }
+    };
+
+    if let Some(raw) = raw_end.as_ref() {
+        let file_alignment = PeHeader::try_new(&mut out)?
+            .nt_hdr
+            .opt_hdr
+            .file_alignment
+            .val();
+
+        // A section without raw data gets it at the end of the file
+        let (start, end) = if raw.is_empty() {
+            let start = align_up(out.len() as u32, file_alignment)? as usize;
+            out.resize(start, 0);
+            (start, start)
+        } else {
+            (raw.start, raw.end)
+        };
+
+        out.splice(end..end, vec![0u8; shift as usize]);
+
+        {
+            let mut pe_hdr = PeHeader::try_new(&mut out)?;
+            shift_file_offsets(&mut pe_hdr, end, shift as i64);
+
+            let s = &mut pe_hdr.sec_hdrs[index];
+            s.ptr_to_raw_data.set(start as u32);
+            s.size_of_raw_data.set((end - start) as u32 + shift);
+
+            let opt_hdr = &mut pe_hdr.nt_hdr.opt_hdr;
+
+            if characteristics & IMAGE_SCN_CNT_CODE != 0 {
+                opt_hdr.size_of_code += shift;
+            }
+
+            if characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0 {
+                opt_hdr.size_of_init_data += shift;
+            }
+        }
+
+        shift_debug_data(&PeHeader::try_new(&mut out)?, end, shift as i64)?;
+    }
+
+    {
+        let mut pe_hdr = PeHeader::try_new(&mut out)?;
+        let s = &mut pe_hdr.sec_hdrs[index];
+        let old_virt_size = s.virt_size.val();
+
+        if old_virt_size < size {
+            s.virt_size.set(size);
+
+            if characteristics & IMAGE_SCN_CNT_UNINITIALIZED_DATA != 0 {
+                let file_alignment = pe_hdr.nt_hdr.opt_hdr.file_alignment.val();
+                pe_hdr.nt_hdr.opt_hdr.size_of_uninit_data +=
+                    align_up(size, file_alignment)? - align_up(old_virt_size, file_alignment)?;
+            }
+        }
+
+        let size_of_image = pe_hdr
+            .next_sec_virt_addr()?
+            .max(pe_hdr.nt_hdr.opt_hdr.size_of_image.val());
+        pe_hdr.nt_hdr.opt_hdr.size_of_image.set(size_of_image);
+    }
+
+    Ok(out)
+}
+
+// Removes section index and its raw data, moving the raw data after it up. Relocation and debug
+// directories pointing into it are cleared, it is an error for the entrypoint or any other
+// directory to. A section in the middle of the image leaves the previous one covering its
+// virtual range, as the loader expects sections to be contiguous
+pub fn remove_section(buf: &[u8], index: usize) -> Result<Vec<u8>, PeError> {
+    let mut out = buf.to_vec();
+
+    let (raw, clear, prev_virt_size, sec_table_offset) = {
+        let pe_hdr = PeHeader::try_new(&mut out)?;
+        let opt_hdr = &pe_hdr.nt_hdr.opt_hdr;
+        let s = pe_hdr
+            .sec_hdrs
+            .get(index)
+            .ok_or(PeError::BadSectionIndex { index })?;
+        let virt_addr = s.virt_addr.val();
+        let virt_end = virt_addr
+            .checked_add(pe_hdr.sec_virt_size(s)?)
+            .ok_or(PeError::AddressOverflow)?;
+        let in_sec = |rva: u32| rva >= virt_addr && rva < virt_end;
+
+        if in_sec(opt_hdr.addr_of_entrypoint.val()) {
+            return Err(PeError::SectionHasEntryPoint { index });
+        }
+
+        let mut clear = Vec::new();
+
+        for dir in DataDirType::ALL.iter() {
+            // The certificate table is given as a file offset and lives outside the sections
+            if *dir == DataDirType::Security {
+                continue;
+            }
+
+            match opt_hdr.data_dirs.rva_and_size(*dir) {
+                Some((rva, _)) if in_sec(rva) => {
+                    if !STRIPPABLE_DIRS.contains(dir) {
+                        return Err(PeError::SectionInUse { index, dir: *dir });
+                    }
+
+                    clear.push(*dir);
+                }
+                _ => (),
+            }
+        }
+
+        let raw = pe_hdr.sec_ranges[index].clone();
+        let in_raw =
+            |offset: u32| matches!(raw.as_ref(), Some(r) if r.contains(&(offset as usize)));
+
+        // A debug directory elsewhere can still point at data in this section
+        let debug_data_in_sec = debug_entries(&pe_hdr)
+            .unwrap_or_default()
+            .iter()
+            .any(|(rva, offset)| (*rva != 0 && in_sec(*rva)) || in_raw(*offset));
+
+        if debug_data_in_sec && !clear.contains(&DataDirType::Debug) {
+            clear.push(DataDirType::Debug);
+        }
+
+        // Raw data shared with another section has to stay
+        let raw = raw.filter(|r| {
+            !pe_hdr.sec_ranges.iter().enumerate().any(|(i, o)| match o {
+                Some(o) => i != index && o.start < r.end && r.start < o.end,
+                None => false,
+            })
+        });
+
+        let prev = pe_hdr
+            .sec_hdrs
+            .iter()
+            .map(|s| s.virt_addr.val())
+            .filter(|va| *va < virt_addr)
+            .max();
+        let next = pe_hdr
+            .sec_hdrs
+            .iter()
+            .map(|s| s.virt_addr.val())
+            .filter(|va| *va > virt_addr)
+            .min();
+        let prev_virt_size = match (prev, next) {
+            (Some(prev), Some(next)) => Some((prev, next - prev)),
+            _ => None,
+        };
+
+        let sec_table_offset = pe_hdr.body_offset - pe_hdr.sec_hdrs.len() * SEC_HDR_SIZE;
+
+        (raw, clear, prev_virt_size, sec_table_offset)
+    };
+
+    {
+        let mut pe_hdr = PeHeader::try_new(&mut out)?;
+
+        for dir in clear.iter() {
+            if let Some(d) = pe_hdr.nt_hdr.opt_hdr.data_dirs.get_mut(*dir) {
+                d.virt_addr.set(0);
+                d.size.set(0);
+            }
+        }
+
+        // Without relocations the image can only be loaded at its preferred base
+        if clear.contains(&DataDirType::Reloc) {
+            let file_characteristics = pe_hdr.nt_hdr.file_hdr.file_characteristics.val();
+            pe_hdr
+                .nt_hdr
+                .file_hdr
+                .file_characteristics
+                .set(file_characteristics | IMAGE_FILE_RELOCS_STRIPPED);
+
+            let dll_characteristics = pe_hdr.nt_hdr.opt_hdr.dll_characteristics.val();
+            pe_hdr
+                .nt_hdr
+                .opt_hdr
+                .dll_characteristics
+                .set(dll_characteristics & !IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE);
+        }
+
+        if let Some((prev, virt_size)) = prev_virt_size {
+            if let Some(p) = pe_hdr
+                .sec_hdrs
+                .iter_mut()
+                .find(|s| s.virt_addr.val() == prev)
+            {
+                p.virt_size.set(virt_size);
+            }
+        }
+
+        let s = &pe_hdr.sec_hdrs[index];
+        let characteristics = s.characteristics.val();
+        let size_of_raw_data = s.size_of_raw_data.val();
+        let virt_size = s.virt_size.val();
+        let opt_hdr = &mut pe_hdr.nt_hdr.opt_hdr;
+
+        if characteristics & IMAGE_SCN_CNT_CODE != 0 {
+            let size = opt_hdr.size_of_code.val().saturating_sub(size_of_raw_data);
+            opt_hdr.size_of_code.set(size);
+        }
+
+        if characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0 {
+            let size = opt_hdr
+                .size_of_init_data
+                .val()
+                .saturating_sub(size_of_raw_data);
+            opt_hdr.size_of_init_data.set(size);
+        }
+
+        if characteristics & IMAGE_SCN_CNT_UNINITIALIZED_DATA != 0 {
+            let size = opt_hdr
+                .size_of_uninit_data
+                .val()
+                .saturating_sub(align_up(virt_size, opt_hdr.file_alignment.val())?);
+            opt_hdr.size_of_uninit_data.set(size);
+        }
+
+        if let Some(r) = raw.as_ref() {
+            shift_file_offsets(&mut pe_hdr, r.end, -(r.len() as i64));
+        }
+    }
+
+    // Move the following section headers up over the removed one
+    let sec_hdr_offset = sec_table_offset + index * SEC_HDR_SIZE;
+    let sec_table_end = {
+        let pe_hdr = PeHeader::try_new(&mut out)?;
+        pe_hdr.body_offset
+    };
+
+    out.copy_within(sec_hdr_offset + SEC_HDR_SIZE..sec_table_end, sec_hdr_offset);
+
+    for b in out[sec_table_end - SEC_HDR_SIZE..sec_table_end].iter_mut() {
+        *b = 0;
+    }
+
+    {
+        let mut pe_hdr = PeHeader::try_new(&mut out)?;
+        let num_of_secs = pe_hdr.nt_hdr.file_hdr.num_of_secs.val();
+        pe_hdr.nt_hdr.file_hdr.num_of_secs.set(num_of_secs - 1);
+    }
+
+    if let Some(r) = raw.as_ref() {
+        out.drain(r.clone());
+        shift_debug_data(&PeHeader::try_new(&mut out)?, r.end, -(r.len() as i64))?;
+    }
+
+    {
+        let mut pe_hdr = PeHeader::try_new(&mut out)?;
+        let size_of_image = pe_hdr.next_sec_virt_addr()?;
+        pe_hdr.nt_hdr.opt_hdr.size_of_image.set(size_of_image);
+    }
+
+    Ok(out)
+}
+
+// Offset of the first section raw data, or the end of the file if there is none
+fn first_raw_data(pe_hdr: &PeHeader, buf_len: usize) -> usize {
+    pe_hdr
+        .sec_ranges
+        .iter()
+        .flatten()
+        .map(|r| r.start)
+        .min()
+        .unwrap_or(buf_len)
+}
+
+// Makes sure there are at least extra bytes free after the section table. size_of_hdrs is grown
+// as needed, and if that runs into the first section's raw data everything from there on is
+// moved down by a multiple of file_alignment, fixing up the file offsets that point past it.
+// The headers can't grow into the first section's virtual address, as that would mean moving
+// sections in memory
+fn grow_headers(buf: &[u8], extra: usize) -> Result<Vec<u8>, PeError> {
+    let mut out = buf.to_vec();
+
+    let grow = {
+        let pe_hdr = PeHeader::try_new(&mut out)?;
+        let opt_hdr = &pe_hdr.nt_hdr.opt_hdr;
+        let needed = pe_hdr.body_offset + extra;
+        let first_raw_data = first_raw_data(&pe_hdr, buf.len());
+
+        if needed <= (opt_hdr.size_of_hdrs.val() as usize).min(first_raw_data) {
+            None
+        } else {
+            let file_alignment = opt_hdr.file_alignment.val();
+            let size_of_hdrs = align_up(needed as u32, file_alignment)?;
+            let first_sec_virt_addr = pe_hdr
+                .sec_hdrs
+                .iter()
+                .map(|s| s.virt_addr.val())
+                .min()
+                .unwrap_or(u32::MAX);
+
+            if align_up(size_of_hdrs, opt_hdr.sec_alignment.val())? > first_sec_virt_addr {
+                return Err(PeError::NoRoomForSectionHeader);
+            }
+
+            let shift = match needed.checked_sub(first_raw_data) {
+                Some(n) => align_up(n as u32, file_alignment)?,
+                None => 0,
+            };
+
+            Some((first_raw_data, size_of_hdrs, shift))
+        }
+    };
+
+    let (first_raw_data, size_of_hdrs, shift) = match grow {
+        Some(g) => g,
+        None => return Ok(out),
+    };
+
+    if shift != 0 {
+        out.splice(first_raw_data..first_raw_data, vec![0u8; shift as usize]);
+    }
+
+    {
+        let mut pe_hdr = PeHeader::try_new(&mut out)?;
+        pe_hdr.nt_hdr.opt_hdr.size_of_hdrs.set(size_of_hdrs);
+
+        if shift != 0 {
+            shift_file_offsets(&mut pe_hdr, first_raw_data, shift as i64);
+        }
+    }
+
+    // Reparsed so the debug directory is found at the section's new raw data
+    if shift != 0 {
+        shift_debug_data(&PeHeader::try_new(&mut out)?, first_raw_data, shift as i64)?;
+    }
+
+    Ok(out)
+}
+
+fn moved(offset: u32, first_raw_data: usize) -> bool {
+    offset != 0 && offset as usize >= first_raw_data
+}
+
+fn shifted(offset: u32, shift: i64) -> u32 {
+    (offset as i64 + shift) as u32
+}
+
+// Moves every file offset in the headers at or past first_raw_data by shift, which is negative
+// when raw data has been removed
+fn shift_file_offsets(pe_hdr: &mut PeHeader, first_raw_data: usize, shift: i64) {
+    let moved = |offset: u32| moved(offset, first_raw_data);
+
+    for s in pe_hdr.sec_hdrs.iter_mut() {
+        if moved(s.ptr_to_raw_data.val()) {
+            s.ptr_to_raw_data
+                .set(shifted(s.ptr_to_raw_data.val(), shift));
+        }
+
+        if moved(s.ptr_to_relocs.val()) {
+            s.ptr_to_relocs.set(shifted(s.ptr_to_relocs.val(), shift));
+        }
+
+        if moved(s.ptr_to_line_nums.val()) {
+            s.ptr_to_line_nums
+                .set(shifted(s.ptr_to_line_nums.val(), shift));
+        }
+    }
+
+    let file_hdr = &mut pe_hdr.nt_hdr.file_hdr;
+
+    if moved(file_hdr.ptr_to_symbol_table.val()) {
+        file_hdr
+            .ptr_to_symbol_table
+            .set(shifted(file_hdr.ptr_to_symbol_table.val(), shift));
+    }
+
+    // The certificate table is the one data directory given as a file offset
+    if let Some(security) = pe_hdr.nt_hdr.opt_hdr.data_dirs.security.as_mut() {
+        if moved(security.virt_addr.val()) {
+            security
+                .virt_addr
+                .set(shifted(security.virt_addr.val(), shift));
+        }
+    }
+}
+
+// (rva, file offset) of each debug directory entry's data
+fn debug_entries(pe_hdr: &PeHeader) -> Result<Vec<(u32, u32)>, PeError> {
+    let (rva, size) = match pe_hdr
+        .nt_hdr
+        .opt_hdr
+        .data_dirs
+        .rva_and_size(DataDirType::Debug)
+    {
+        Some(d) => d,
+        None => return Ok(Vec::new()),
+    };
+
+    let entries = pe_hdr.data_at_rva(rva)?;
+    let len = entries.len().min(size as usize);
+
+    Ok(entries[..len]
+        .chunks_exact(DEBUG_DIR_ENTRY_SIZE)
+        .map(|e| {
+            (
+                LittleEndian::read_u32(&e[0x14..]),
+                LittleEndian::read_u32(&e[0x18..]),
+            )
+        })
+        .collect())
+}
+
+// Debug directory entries give the file offset of their data as well as the rva
+fn shift_debug_data(pe_hdr: &PeHeader, first_raw_data: usize, shift: i64) -> Result<(), PeError> {
+    let debug = pe_hdr
+        .nt_hdr
+        .opt_hdr
+        .data_dirs
+        .rva_and_size(DataDirType::Debug);
+
+    if let Some((rva, size)) = debug {
+        let mut entries = pe_hdr.data_at_rva_mut(rva)?;
+        let len = entries.len().min(size as usize);
+
+        for entry in entries[..len].chunks_exact_mut(DEBUG_DIR_ENTRY_SIZE) {
+            let ptr_to_raw_data = LittleEndian::read_u32(&entry[0x18..]);
+
+            if moved(ptr_to_raw_data, first_raw_data) {
+                LittleEndian::write_u32(&mut entry[0x18..], shifted(ptr_to_raw_data, shift));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "std_unit_tests")]
+#[cfg(test)]
+use crate::pe::{read_test_pe, read_test_pe32};
+
+#[test]
+fn append_section_code() {
+    let buf = read_test_pe();
+    let data = [0xCCu8; 0x10];
+    let mut out = append_section(
+        &buf,
+        b".text2",
+        IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ,
+        &data,
+    )
+    .unwrap();
+
+    assert_eq_hex!(out.len(), 0x1000);
+
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    let opt_hdr = &pe_hdr.nt_hdr.opt_hdr;
+    let s = &pe_hdr.sec_hdrs[5];
+
+    assert_eq_hex!(pe_hdr.nt_hdr.file_hdr.num_of_secs.val(), 6);
+    assert_eq!(*s.name.as_ref(), b".text2\0\0");
+    assert_eq_hex!(s.virt_addr.val(), 0x6000);
+    assert_eq_hex!(s.virt_size.val(), 0x10);
+    assert_eq_hex!(s.ptr_to_raw_data.val(), 0xE00);
+    assert_eq_hex!(s.size_of_raw_data.val(), 0x200);
+    assert_eq_hex!(opt_hdr.size_of_image.val(), 0x7000);
+    assert_eq_hex!(opt_hdr.size_of_hdrs.val(), 0x400);
+    assert_eq!(&pe_hdr.sec(5).unwrap()[..0x10], &data[..]);
+
+    assert_eq!(
+        append_section(&buf, b".toolong0", 0, &data),
+        Err(PeError::SectionNameTooLong { len: 9 })
+    );
+}
+
+#[test]
+fn append_section_shift() {
+    let orig = read_test_pe();
+    let mut buf = orig.clone();
+
+    // The headers fit 9 more section headers before the first section's raw data
+    for i in 0..10 {
+        let name = [b'.', b's', b'0' + i];
+        buf = append_section(&buf, &name, IMAGE_SCN_CNT_INITIALIZED_DATA, &[i]).unwrap();
+    }
+
+    let mut orig = orig;
+    let orig_hdr = PeHeader::try_new(&mut orig).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut buf).unwrap();
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 15);
+    assert_eq_hex!(pe_hdr.nt_hdr.opt_hdr.size_of_hdrs.val(), 0x600);
+    assert_eq_hex!(pe_hdr.sec_hdrs[0].ptr_to_raw_data.val(), 0x600);
+    assert_eq_hex!(pe_hdr.sec_hdrs[14].ptr_to_raw_data.val(), 0x2200);
+    assert_eq_hex!(pe_hdr.sec_hdrs[14].virt_addr.val(), 0xF000);
+
+    for i in [0, 1, 3, 4].iter() {
+        assert_eq!(*pe_hdr.sec(*i).unwrap(), *orig_hdr.sec(*i).unwrap());
+    }
+
+    for i in 0..10 {
+        assert_eq!(pe_hdr.sec(5 + i).unwrap()[0], i as u8);
+    }
+
+    // The debug entry's raw data pointer moves with the data
+    let debug = pe_hdr.data_at_rva(0x3020).unwrap();
+    assert_eq_hex!(LittleEndian::read_u32(&debug[0x18..]), 0xA3C);
+    assert_eq!(pe_hdr.imports().unwrap(), orig_hdr.imports().unwrap());
+}
+
+#[test]
+fn append_section_no_room() {
+    let mut buf = read_test_pe32();
+
+    // Growing the headers would run into .text mapped at 0x200
+    LittleEndian::write_u32(&mut buf[0x184..], 0x200);
+
+    assert_eq!(
+        append_section(&buf, b".new", 0, &[0]),
+        Err(PeError::NoRoomForSectionHeader)
+    );
+}
+
+#[test]
+fn grow_last_section() {
+    let buf = read_test_pe32();
+    let mut out = grow_section(&buf, 2, 0x300).unwrap();
+
+    assert_eq_hex!(out.len(), 0xA00);
+    assert_eq!(out[0x200..0x800], buf[0x200..]);
+
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    let s = &pe_hdr.sec_hdrs[2];
+
+    assert_eq_hex!(s.virt_size.val(), 0x300);
+    assert_eq_hex!(s.ptr_to_raw_data.val(), 0x600);
+    assert_eq_hex!(s.size_of_raw_data.val(), 0x400);
+    assert_eq_hex!(pe_hdr.nt_hdr.opt_hdr.size_of_image.val(), 0x4000);
+    assert_eq_hex!(pe_hdr.nt_hdr.opt_hdr.size_of_init_data.val(), 0x600);
+}
+
+#[test]
+fn grow_section_shift() {
+    let buf = read_test_pe32();
+    let mut out = grow_section(&buf, 0, 0x300).unwrap();
+
+    assert_eq_hex!(out.len(), 0xA00);
+
+    let mut orig = buf.clone();
+    let orig_hdr = PeHeader::try_new(&mut orig).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    let opt_hdr = &pe_hdr.nt_hdr.opt_hdr;
+
+    assert_eq_hex!(pe_hdr.sec_hdrs[0].virt_size.val(), 0x300);
+    assert_eq_hex!(pe_hdr.sec_hdrs[0].size_of_raw_data.val(), 0x400);
+    assert_eq_hex!(pe_hdr.sec_hdrs[1].ptr_to_raw_data.val(), 0x600);
+    assert_eq_hex!(pe_hdr.sec_hdrs[2].ptr_to_raw_data.val(), 0x800);
+    assert_eq_hex!(opt_hdr.size_of_code.val(), 0x400);
+    assert_eq_hex!(opt_hdr.size_of_image.val(), 0x4000);
+
+    assert_eq!(pe_hdr.sec(0).unwrap()[..0x200], *orig_hdr.sec(0).unwrap());
+    assert_eq!(*pe_hdr.sec(1).unwrap(), *orig_hdr.sec(1).unwrap());
+    assert_eq!(pe_hdr.imports().unwrap(), orig_hdr.imports().unwrap());
+
+    // .text can use the rest of its page but not run into .rdata
+    assert!(grow_section(&buf, 0, 0x1000).is_ok());
+    assert_eq!(
+        grow_section(&buf, 0, 0x1001),
+        Err(PeError::SectionWouldOverlap { index: 0 })
+    );
+    assert_eq!(
+        grow_section(&buf, 3, 0x10),
+        Err(PeError::BadSectionIndex { index: 3 })
+    );
+}
+
+#[test]
+fn grow_section_debug_data() {
+    let mut out = grow_section(&read_test_pe(), 0, 0x201).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+
+    assert_eq_hex!(pe_hdr.sec_hdrs[2].ptr_to_raw_data.val(), 0xA00);
+
+    let debug = pe_hdr.data_at_rva(0x3020).unwrap();
+    assert_eq_hex!(LittleEndian::read_u32(&debug[0x18..]), 0xA3C);
+}
+
+#[test]
+fn remove_reloc_section() {
+    let mut buf = read_test_pe32();
+    PeHeader::try_new(&mut buf)
+        .unwrap()
+        .rebase(0x2000_0000)
+        .unwrap();
+
+    let mut out = remove_section(&buf, 2).unwrap();
+    assert_eq_hex!(out.len(), 0x600);
+    assert_eq!(out[0x200..], buf[0x200..0x600]);
+
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+    let nt_hdr = &pe_hdr.nt_hdr;
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 2);
+    assert_eq!(*pe_hdr.sec_hdrs[1].name.as_ref(), b".rdata\0\0");
+    assert_eq_hex!(nt_hdr.opt_hdr.size_of_image.val(), 0x3000);
+    assert_eq!(
+        nt_hdr.opt_hdr.data_dirs.rva_and_size(DataDirType::Reloc),
+        None
+    );
+    assert_ne!(
+        nt_hdr.file_hdr.file_characteristics.val() & IMAGE_FILE_RELOCS_STRIPPED,
+        0
+    );
+    assert_eq!(pe_hdr.imports().unwrap().len(), 2);
+    assert_eq!(pe_hdr.exports().unwrap().count(), 4);
+}
+
+#[test]
+fn remove_middle_section() {
+    let orig = read_test_pe();
+    let mut out = remove_section(&orig, 3).unwrap();
+    let mut orig = orig;
+    let orig_hdr = PeHeader::try_new(&mut orig).unwrap();
+    let pe_hdr = PeHeader::try_new(&mut out).unwrap();
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 4);
+    assert_eq!(*pe_hdr.sec_hdrs[3].name.as_ref(), b".reloc\0\0");
+    assert_eq_hex!(pe_hdr.sec_hdrs[3].ptr_to_raw_data.val(), 0xA00);
+    assert_eq_hex!(pe_hdr.sec_hdrs[2].virt_size.val(), 0x2000);
+    assert_eq_hex!(pe_hdr.nt_hdr.opt_hdr.size_of_image.val(), 0x6000);
+    assert_eq!(*pe_hdr.sec(3).unwrap(), *orig_hdr.sec(4).unwrap());
+    assert_eq!(pe_hdr.relocations().unwrap().len(), 1);
+    assert_eq!(pe_hdr.imports().unwrap(), orig_hdr.imports().unwrap());
+}
+
+#[test]
+fn remove_section_in_use() {
+    let buf = read_test_pe32();
+
+    assert_eq!(
+        remove_section(&buf, 0),
+        Err(PeError::SectionHasEntryPoint { index: 0 })
+    );
+    assert_eq!(
+        remove_section(&buf, 1),
+        Err(PeError::SectionInUse {
+            index: 1,
+            dir: DataDirType::Export
+        })
+    );
+    assert_eq!(
+        remove_section(&buf, 3),
+        Err(PeError::BadSectionIndex { index: 3 })
+    );
+
+    let mut buf = read_test_pe();
+    PeHeader::new(&mut buf).sec_hdrs[4]
+        .virt_addr
+        .set(0xFFFF_F000);
+
+    assert_eq!(remove_section(&buf, 4), Err(PeError::AddressOverflow));
+}
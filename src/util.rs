@@ -1,19 +1,18 @@
-use alloc::format;
+use crate::error::PeError;
 use alloc::prelude::v1::*;
 use byteorder::ByteOrder;
 
-#[macro_export]
-macro_rules! fmt_err {
-    ($($arg:tt)*) => {{
-        let res = alloc::fmt::format(format_args!($($arg)*));
-
-        format!(
-            "[-] Error:\n\t- Cause: {}\n\t- Line: {}\n\t- File: {}\n",
-            res,
-            line!(),
-            file!(),
-        )
-    }}
+// Rounds val up to a multiple of align, an align of 0 leaves val as is. Sizes from the file can
+// be close enough to u32::MAX for this to overflow
+pub fn align_up(val: u32, align: u32) -> Result<u32, PeError> {
+    if align == 0 {
+        return Ok(val);
+    }
+
+    match val % align {
+        0 => Ok(val),
+        r => val.checked_add(align - r).ok_or(PeError::AddressOverflow),
+    }
 }
 
 pub trait IterWriteBack<'a> {
@@ -24,12 +23,14 @@ pub trait IterWriteBack<'a> {
     where
         Self::Iter: Iterator;
 
-    fn write_single(buf: &mut RWCursor, item: &Self::Output);
+    fn write_single(buf: &mut RWCursor, item: &Self::Output) -> Result<(), PeError>;
 
-    fn write_all(buf: &mut RWCursor, item_vec: &Vec<Self::Output>) {
+    fn write_all(buf: &mut RWCursor, item_vec: &Vec<Self::Output>) -> Result<(), PeError> {
         for i in item_vec {
-            Self::write_single(buf, i)
+            Self::write_single(buf, i)?;
         }
+
+        Ok(())
     }
 }
 
@@ -55,14 +56,64 @@ impl<'a> RWCursor<'a> {
     }
 }
 
+#[macro_use]
+macro_rules! impl_cursor_pos {
+    ($cursor:ident) => {
+        impl<'a> $cursor<'a> {
+            pub fn position(&self) -> usize {
+                self.pos
+            }
+
+            pub fn remaining(&self) -> usize {
+                self.buf.len() - self.pos
+            }
+
+            // Seeking to the end is allowed, anything past it is not
+            pub fn seek(&mut self, pos: usize) -> Result<(), PeError> {
+                if pos > self.buf.len() {
+                    return Err(PeError::Truncated {
+                        offset: self.buf.len(),
+                        needed: pos - self.buf.len(),
+                    });
+                }
+
+                self.pos = pos;
+                Ok(())
+            }
+
+            fn check(&self, needed: usize) -> Result<core::ops::Range<usize>, PeError> {
+                if self.remaining() < needed {
+                    return Err(PeError::Truncated {
+                        offset: self.pos,
+                        needed,
+                    });
+                }
+
+                Ok(self.pos..self.pos + needed)
+            }
+
+            pub fn read_u8(&mut self) -> Result<u8, PeError> {
+                let r = self.buf[self.check(1)?.start];
+                self.pos += 1;
+                Ok(r)
+            }
+
+            pub fn read_i8(&mut self) -> Result<i8, PeError> {
+                self.read_u8().map(|b| b as i8)
+            }
+        }
+    };
+}
+
 #[macro_use]
 macro_rules! impl_ro_cursor {
     ($read_name:ident, $typ:ty) => {
         impl<'a> ROCursor<'a> {
-            pub fn $read_name<E: ByteOrder>(&mut self) -> $typ {
-                let r = E::$read_name(&self.buf[self.pos..self.buf.len()]);
+            pub fn $read_name<E: ByteOrder>(&mut self) -> Result<$typ, PeError> {
+                let range = self.check(core::mem::size_of::<$typ>())?;
+                let r = E::$read_name(&self.buf[range]);
                 self.pos += core::mem::size_of::<$typ>();
-                r
+                Ok(r)
             }
         }
     };
@@ -72,27 +123,127 @@ macro_rules! impl_ro_cursor {
 macro_rules! impl_rw_cursor {
     ($read_name:ident, $write_name:ident, $typ:ty) => {
         impl<'a> RWCursor<'a> {
-            pub fn $read_name<E: ByteOrder>(&mut self) -> $typ {
-                let r = E::$read_name(&self.buf[self.pos..self.buf.len()]);
+            pub fn $read_name<E: ByteOrder>(&mut self) -> Result<$typ, PeError> {
+                let range = self.check(core::mem::size_of::<$typ>())?;
+                let r = E::$read_name(&self.buf[range]);
                 self.pos += core::mem::size_of::<$typ>();
-                r
+                Ok(r)
             }
 
-            pub fn $write_name<E: ByteOrder>(&mut self, val: $typ) {
-                let buf_len = self.buf.len();
-                E::$write_name(&mut self.buf[self.pos..buf_len], val);
+            pub fn $write_name<E: ByteOrder>(&mut self, val: $typ) -> Result<(), PeError> {
+                let range = self.check(core::mem::size_of::<$typ>())?;
+                E::$write_name(&mut self.buf[range], val);
                 self.pos += core::mem::size_of::<$typ>();
+                Ok(())
             }
         }
     };
 }
 
+impl_cursor_pos!(ROCursor);
+impl_cursor_pos!(RWCursor);
+
+impl<'a> RWCursor<'a> {
+    pub fn write_u8(&mut self, val: u8) -> Result<(), PeError> {
+        let i = self.check(1)?.start;
+        self.buf[i] = val;
+        self.pos += 1;
+        Ok(())
+    }
+
+    pub fn write_i8(&mut self, val: i8) -> Result<(), PeError> {
+        self.write_u8(val as u8)
+    }
+}
+
 impl_ro_cursor!(read_u16, u16);
 impl_ro_cursor!(read_u32, u32);
 impl_ro_cursor!(read_u64, u64);
 impl_ro_cursor!(read_u128, u128);
+impl_ro_cursor!(read_i16, i16);
+impl_ro_cursor!(read_i32, i32);
+impl_ro_cursor!(read_i64, i64);
+impl_ro_cursor!(read_i128, i128);
 
 impl_rw_cursor!(read_u16, write_u16, u16);
 impl_rw_cursor!(read_u32, write_u32, u32);
 impl_rw_cursor!(read_u64, write_u64, u64);
 impl_rw_cursor!(read_u128, write_u128, u128);
+impl_rw_cursor!(read_i16, write_i16, i16);
+impl_rw_cursor!(read_i32, write_i32, i32);
+impl_rw_cursor!(read_i64, write_i64, i64);
+impl_rw_cursor!(read_i128, write_i128, i128);
+
+#[cfg(feature = "std_unit_tests")]
+#[cfg(test)]
+use byteorder::LittleEndian;
+
+#[test]
+fn align_up_overflow() {
+    assert_eq!(align_up(0x1001, 0x1000), Ok(0x2000));
+    assert_eq!(align_up(0x1000, 0x1000), Ok(0x1000));
+    assert_eq!(align_up(0x1234, 0), Ok(0x1234));
+    assert_eq!(align_up(0xFFFF_F000, 0x1000), Ok(0xFFFF_F000));
+    assert_eq!(align_up(0xFFFF_F001, 0x1000), Err(PeError::AddressOverflow));
+}
+
+#[test]
+fn ro_cursor() {
+    let buf = [0x01, 0xFF, 0x34, 0x12, 0xFE, 0xFF, 0xFF, 0xFF, 0xAA];
+    let mut cur = ROCursor::new(&buf);
+
+    assert_eq!(cur.read_u8(), Ok(1));
+    assert_eq!(cur.read_i8(), Ok(-1));
+    assert_eq!(cur.read_u16::<LittleEndian>(), Ok(0x1234));
+    assert_eq!(cur.read_i32::<LittleEndian>(), Ok(-2));
+    assert_eq!(cur.position(), 8);
+    assert_eq!(cur.remaining(), 1);
+    assert_eq!(
+        cur.read_u16::<LittleEndian>(),
+        Err(PeError::Truncated {
+            offset: 8,
+            needed: 2
+        })
+    );
+    assert_eq!(cur.position(), 8);
+
+    cur.seek(2).unwrap();
+    assert_eq!(cur.read_u16::<LittleEndian>(), Ok(0x1234));
+    cur.seek(buf.len()).unwrap();
+    assert_eq!(cur.remaining(), 0);
+    assert_eq!(
+        cur.seek(buf.len() + 1),
+        Err(PeError::Truncated {
+            offset: buf.len(),
+            needed: 1
+        })
+    );
+}
+
+#[test]
+fn rw_cursor() {
+    let mut buf = [0u8; 7];
+    let mut cur = RWCursor::new(&mut buf);
+
+    cur.write_u8(0xAA).unwrap();
+    cur.write_i16::<LittleEndian>(-2).unwrap();
+    cur.write_u32::<LittleEndian>(0x1234_5678).unwrap();
+    assert_eq!(
+        cur.write_u8(0),
+        Err(PeError::Truncated {
+            offset: 7,
+            needed: 1
+        })
+    );
+
+    cur.seek(1).unwrap();
+    assert_eq!(cur.read_i16::<LittleEndian>(), Ok(-2));
+    assert_eq!(
+        cur.write_u64::<LittleEndian>(0),
+        Err(PeError::Truncated {
+            offset: 3,
+            needed: 8
+        })
+    );
+    assert_eq!(buf, [0xAA, 0xFE, 0xFF, 0x78, 0x56, 0x34, 0x12]);
+}
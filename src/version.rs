@@ -0,0 +1,558 @@
+use crate::{
+    error::PeError,
+    pe::PeHeader,
+    resources::{replace_resource_data, ResourceId, RT_VERSION},
+    util::{align_up, ROCursor, RWCursor},
+};
+use alloc::prelude::v1::*;
+#[allow(unused_imports)]
+use assert_hex::assert_eq_hex;
+use byteorder::{ByteOrder, LittleEndian};
+use core::ops::Range;
+
+pub const VS_VERSION_INFO_KEY: &str = "VS_VERSION_INFO";
+pub const STRING_FILE_INFO_KEY: &str = "StringFileInfo";
+pub const VAR_FILE_INFO_KEY: &str = "VarFileInfo";
+pub const TRANSLATION_KEY: &str = "Translation";
+pub const VS_FIXEDFILEINFO_SIGNATURE: u32 = 0xFEEF_04BD;
+pub const VS_FIXEDFILEINFO_SIZE: usize = 0x34;
+// US English, Unicode. Used for the string table set creates when there is none
+pub const DEFAULT_STRING_TABLE_KEY: &str = "040904B0";
+
+const BLOCK_HDR_SIZE: usize = 6;
+const BLOCK_TYPE_BINARY: u16 = 0;
+const BLOCK_TYPE_TEXT: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FixedFileInfo {
+    pub signature: u32,
+    pub struc_version: u32,
+    pub file_version_ms: u32,
+    pub file_version_ls: u32,
+    pub product_version_ms: u32,
+    pub product_version_ls: u32,
+    pub file_flags_mask: u32,
+    pub file_flags: u32,
+    pub file_os: u32,
+    pub file_type: u32,
+    pub file_subtype: u32,
+    pub file_date_ms: u32,
+    pub file_date_ls: u32,
+}
+
+impl FixedFileInfo {
+    pub fn new(buf: &[u8]) -> Result<Self, PeError> {
+        let mut cur = ROCursor::new(buf);
+
+        Ok(Self {
+            signature: cur.read_u32::<LittleEndian>()?,
+            struc_version: cur.read_u32::<LittleEndian>()?,
+            file_version_ms: cur.read_u32::<LittleEndian>()?,
+            file_version_ls: cur.read_u32::<LittleEndian>()?,
+            product_version_ms: cur.read_u32::<LittleEndian>()?,
+            product_version_ls: cur.read_u32::<LittleEndian>()?,
+            file_flags_mask: cur.read_u32::<LittleEndian>()?,
+            file_flags: cur.read_u32::<LittleEndian>()?,
+            file_os: cur.read_u32::<LittleEndian>()?,
+            file_type: cur.read_u32::<LittleEndian>()?,
+            file_subtype: cur.read_u32::<LittleEndian>()?,
+            file_date_ms: cur.read_u32::<LittleEndian>()?,
+            file_date_ls: cur.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    pub fn write(&self, buf: &mut RWCursor) -> Result<(), PeError> {
+        buf.write_u32::<LittleEndian>(self.signature)?;
+        buf.write_u32::<LittleEndian>(self.struc_version)?;
+        buf.write_u32::<LittleEndian>(self.file_version_ms)?;
+        buf.write_u32::<LittleEndian>(self.file_version_ls)?;
+        buf.write_u32::<LittleEndian>(self.product_version_ms)?;
+        buf.write_u32::<LittleEndian>(self.product_version_ls)?;
+        buf.write_u32::<LittleEndian>(self.file_flags_mask)?;
+        buf.write_u32::<LittleEndian>(self.file_flags)?;
+        buf.write_u32::<LittleEndian>(self.file_os)?;
+        buf.write_u32::<LittleEndian>(self.file_type)?;
+        buf.write_u32::<LittleEndian>(self.file_subtype)?;
+        buf.write_u32::<LittleEndian>(self.file_date_ms)?;
+        buf.write_u32::<LittleEndian>(self.file_date_ls)
+    }
+
+    // Major, minor, build, revision
+    pub fn file_version(&self) -> [u16; 4] {
+        split_version(self.file_version_ms, self.file_version_ls)
+    }
+
+    pub fn set_file_version(&mut self, version: [u16; 4]) {
+        let (ms, ls) = join_version(version);
+        self.file_version_ms = ms;
+        self.file_version_ls = ls;
+    }
+
+    pub fn product_version(&self) -> [u16; 4] {
+        split_version(self.product_version_ms, self.product_version_ls)
+    }
+
+    pub fn set_product_version(&mut self, version: [u16; 4]) {
+        let (ms, ls) = join_version(version);
+        self.product_version_ms = ms;
+        self.product_version_ls = ls;
+    }
+}
+
+fn split_version(ms: u32, ls: u32) -> [u16; 4] {
+    [(ms >> 16) as u16, ms as u16, (ls >> 16) as u16, ls as u16]
+}
+
+fn join_version(version: [u16; 4]) -> (u32, u32) {
+    (
+        (version[0] as u32) << 16 | version[1] as u32,
+        (version[2] as u32) << 16 | version[3] as u32,
+    )
+}
+
+// A StringFileInfo table, key is the language and code page in hex, e.g. "040904B0"
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StringTable {
+    pub key: String,
+    pub strings: Vec<(String, String)>,
+}
+
+// VS_VERSIONINFO. Only the fixed info, the string tables and the translations are kept, any other
+// blocks are dropped when it is rebuilt
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VersionInfo {
+    pub fixed: Option<FixedFileInfo>,
+    pub string_tables: Vec<StringTable>,
+    pub translations: Vec<(u16, u16)>, // (language, code page)
+}
+
+// Every block is a length, value length and type, a null terminated UTF-16 key, the value and
+// then child blocks. The value and each child start on a 4 byte boundary
+struct Block {
+    offset: usize,
+    key: String,
+    value_type: u16,
+    value: Range<usize>,
+    children: Range<usize>,
+}
+
+fn align4(offset: usize) -> Result<usize, PeError> {
+    Ok(align_up(offset as u32, 4)? as usize)
+}
+
+fn read_block(buf: &[u8], offset: usize) -> Result<Block, PeError> {
+    let mut cur = ROCursor::new(buf);
+    cur.seek(offset)?;
+
+    let len = cur.read_u16::<LittleEndian>()? as usize;
+    let value_len = cur.read_u16::<LittleEndian>()? as usize;
+    let value_type = cur.read_u16::<LittleEndian>()?;
+    let end = offset + len;
+
+    if len < BLOCK_HDR_SIZE || end > buf.len() {
+        return Err(PeError::BadVersionInfo { offset });
+    }
+
+    let mut units = Vec::new();
+
+    loop {
+        match cur.read_u16::<LittleEndian>()? {
+            0 => break,
+            u => units.push(u),
+        }
+    }
+
+    if cur.position() > end {
+        return Err(PeError::BadVersionInfo { offset });
+    }
+
+    // Text values are counted in UTF-16 code units
+    let value_size = match value_type {
+        BLOCK_TYPE_TEXT => value_len * 2,
+        _ => value_len,
+    };
+    let value_start = align4(cur.position())?.min(end);
+    let value_end = (value_start + value_size).min(end);
+
+    Ok(Block {
+        offset,
+        key: String::from_utf16_lossy(&units),
+        value_type,
+        value: value_start..value_end,
+        children: align4(value_end)?.min(end)..end,
+    })
+}
+
+fn child_blocks(buf: &[u8], parent: &Block) -> Result<Vec<Block>, PeError> {
+    let mut blocks = Vec::new();
+    let mut offset = parent.children.start;
+
+    while offset < parent.children.end {
+        let block = read_block(&buf[..parent.children.end], offset)?;
+        offset = align4(block.children.end)?;
+        blocks.push(block);
+    }
+
+    Ok(blocks)
+}
+
+// Some linkers count the value length in bytes, so text values are read up to the null
+// terminator or the end of the block instead
+fn text_value(buf: &[u8], block: &Block) -> String {
+    let units: Vec<u16> = buf[block.value.start..block.children.end]
+        .chunks_exact(2)
+        .map(LittleEndian::read_u16)
+        .take_while(|&u| u != 0)
+        .collect();
+
+    String::from_utf16_lossy(&units)
+}
+
+fn utf16z(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity((s.len() + 1) * 2);
+
+    for u in s.encode_utf16().chain(core::iter::once(0)) {
+        out.extend_from_slice(&u.to_le_bytes());
+    }
+
+    out
+}
+
+fn build_block(
+    key: &str,
+    value_type: u16,
+    value: &[u8],
+    value_len: usize,
+    children: &[Vec<u8>],
+) -> Result<Vec<u8>, PeError> {
+    let mut out = vec![0; BLOCK_HDR_SIZE];
+    out.extend_from_slice(&utf16z(key));
+    out.resize(align4(out.len())?, 0);
+    out.extend_from_slice(value);
+
+    for child in children {
+        out.resize(align4(out.len())?, 0);
+        out.extend_from_slice(child);
+    }
+
+    // Both lengths are u16 fields, too many or too long strings don't fit
+    if out.len() > u16::MAX as usize || value_len > u16::MAX as usize {
+        return Err(PeError::VersionBlockTooLarge { len: out.len() });
+    }
+
+    let len = out.len() as u16;
+    LittleEndian::write_u16(&mut out[0..], len);
+    LittleEndian::write_u16(&mut out[2..], value_len as u16);
+    LittleEndian::write_u16(&mut out[4..], value_type);
+    Ok(out)
+}
+
+impl VersionInfo {
+    pub fn new(buf: &[u8]) -> Result<Self, PeError> {
+        let root = read_block(buf, 0)?;
+
+        if root.key != VS_VERSION_INFO_KEY {
+            return Err(PeError::BadVersionInfo { offset: 0 });
+        }
+
+        let fixed = if root.value.is_empty() {
+            None
+        } else {
+            let fixed = FixedFileInfo::new(&buf[root.value.clone()])?;
+
+            if fixed.signature != VS_FIXEDFILEINFO_SIGNATURE {
+                return Err(PeError::BadVersionInfo {
+                    offset: root.value.start,
+                });
+            }
+
+            Some(fixed)
+        };
+
+        let mut info = Self {
+            fixed,
+            ..Self::default()
+        };
+
+        for child in child_blocks(buf, &root)? {
+            match child.key.as_str() {
+                STRING_FILE_INFO_KEY => {
+                    for table in child_blocks(buf, &child)? {
+                        let mut strings = Vec::new();
+
+                        for s in child_blocks(buf, &table)? {
+                            strings.push((s.key.clone(), text_value(buf, &s)));
+                        }
+
+                        info.string_tables.push(StringTable {
+                            key: table.key,
+                            strings,
+                        });
+                    }
+                }
+                VAR_FILE_INFO_KEY => {
+                    for var in child_blocks(buf, &child)? {
+                        if var.key != TRANSLATION_KEY || var.value_type != BLOCK_TYPE_BINARY {
+                            continue;
+                        }
+
+                        if var.value.len() % 4 != 0 {
+                            return Err(PeError::BadVersionInfo { offset: var.offset });
+                        }
+
+                        info.translations
+                            .extend(buf[var.value.clone()].chunks(4).map(|t| {
+                                (
+                                    LittleEndian::read_u16(&t[0..]),
+                                    LittleEndian::read_u16(&t[2..]),
+                                )
+                            }));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(info)
+    }
+
+    // Value from the first string table that has key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.string_tables
+            .iter()
+            .flat_map(|t| t.strings.iter())
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    // Sets key in every string table, adding it where it is missing
+    pub fn set(&mut self, key: &str, value: &str) {
+        if self.string_tables.is_empty() {
+            self.string_tables.push(StringTable {
+                key: DEFAULT_STRING_TABLE_KEY.to_string(),
+                strings: Vec::new(),
+            });
+        }
+
+        for table in self.string_tables.iter_mut() {
+            match table.strings.iter_mut().find(|(k, _)| k == key) {
+                Some((_, v)) => *v = value.to_string(),
+                None => table.strings.push((key.to_string(), value.to_string())),
+            }
+        }
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>, PeError> {
+        let mut fixed = Vec::new();
+
+        if let Some(f) = self.fixed {
+            fixed.resize(VS_FIXEDFILEINFO_SIZE, 0);
+            f.write(&mut RWCursor::new(&mut fixed))?;
+        }
+
+        let mut children = Vec::new();
+
+        if !self.string_tables.is_empty() {
+            let mut tables = Vec::new();
+
+            for t in self.string_tables.iter() {
+                let mut strings = Vec::new();
+
+                for (k, v) in t.strings.iter() {
+                    let value = utf16z(v);
+                    strings.push(build_block(
+                        k,
+                        BLOCK_TYPE_TEXT,
+                        &value,
+                        value.len() / 2,
+                        &[],
+                    )?);
+                }
+
+                tables.push(build_block(&t.key, BLOCK_TYPE_TEXT, &[], 0, &strings)?);
+            }
+
+            children.push(build_block(
+                STRING_FILE_INFO_KEY,
+                BLOCK_TYPE_TEXT,
+                &[],
+                0,
+                &tables,
+            )?);
+        }
+
+        if !self.translations.is_empty() {
+            let mut value = vec![0; self.translations.len() * 4];
+
+            for (t, (lang, code_page)) in value.chunks_mut(4).zip(self.translations.iter()) {
+                LittleEndian::write_u16(&mut t[0..], *lang);
+                LittleEndian::write_u16(&mut t[2..], *code_page);
+            }
+
+            let var = build_block(TRANSLATION_KEY, BLOCK_TYPE_BINARY, &value, value.len(), &[])?;
+            children.push(build_block(
+                VAR_FILE_INFO_KEY,
+                BLOCK_TYPE_TEXT,
+                &[],
+                0,
+                &[var],
+            )?);
+        }
+
+        build_block(
+            VS_VERSION_INFO_KEY,
+            BLOCK_TYPE_BINARY,
+            &fixed,
+            fixed.len(),
+            &children,
+        )
+    }
+
+    // Replaces the data of the first RT_VERSION resource
+    pub fn write(&self, buf: &[u8]) -> Result<Vec<u8>, PeError> {
+        let mut out = buf.to_vec();
+        let resource = PeHeader::try_new(&mut out)?
+            .find_resource(&ResourceId::Id(RT_VERSION), None)?
+            .ok_or(PeError::ResourceNotFound)?;
+
+        replace_resource_data(buf, &resource, &self.build()?)
+    }
+}
+
+impl<'a> PeHeader<'a> {
+    pub fn version_info(&self) -> Result<Option<VersionInfo>, PeError> {
+        match self.find_resource(&ResourceId::Id(RT_VERSION), None)? {
+            Some(r) => Ok(Some(VersionInfo::new(&r.data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+//Tests
+#[cfg(feature = "std_unit_tests")]
+#[cfg(test)]
+use crate::pe::{read_test_pe, read_test_pe64};
+
+#[test]
+fn version_info() {
+    let mut buf = read_test_pe64();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let info = pe_hdr.version_info().unwrap().unwrap();
+    let fixed = info.fixed.unwrap();
+
+    assert_eq_hex!(fixed.signature, VS_FIXEDFILEINFO_SIGNATURE);
+    assert_eq!(fixed.file_version(), [1, 2, 3, 4]);
+    assert_eq!(fixed.product_version(), [1, 2, 3, 4]);
+    assert_eq_hex!(fixed.file_type, 2);
+
+    assert_eq!(info.string_tables.len(), 1);
+    assert_eq!(info.string_tables[0].key, "040904B0");
+    assert_eq!(info.string_tables[0].strings.len(), 5);
+    assert_eq!(info.get("CompanyName"), Some("Zeo Test Co"));
+    assert_eq!(info.get("FileVersion"), Some("1.2.3.4"));
+    assert_eq!(info.get("LegalCopyright"), None);
+    assert_eq!(info.translations, vec![(0x409, 0x4B0)]);
+
+    // Rebuilding an unmodified version info gives back the same bytes
+    let resource = pe_hdr
+        .find_resource(&ResourceId::Id(RT_VERSION), None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(info.build().unwrap(), resource.data);
+
+    let mut buf = read_test_pe();
+    let pe_hdr = PeHeader::new(&mut buf);
+    assert_eq!(pe_hdr.version_info(), Ok(None));
+}
+
+#[test]
+fn version_info_bad() {
+    let mut buf = read_test_pe64();
+    let pe_hdr = PeHeader::new(&mut buf);
+    let mut data = pe_hdr
+        .find_resource(&ResourceId::Id(RT_VERSION), None)
+        .unwrap()
+        .unwrap()
+        .data;
+
+    // Signature
+    data[0x28] = 0;
+    assert_eq!(
+        VersionInfo::new(&data),
+        Err(PeError::BadVersionInfo { offset: 0x28 })
+    );
+
+    // StringFileInfo longer than the whole thing
+    data[0x28] = 0xBD;
+    data[0x5C] = 0xFF;
+    assert_eq!(
+        VersionInfo::new(&data),
+        Err(PeError::BadVersionInfo { offset: 0x5C })
+    );
+
+    data[6] = b'X';
+    assert_eq!(
+        VersionInfo::new(&data),
+        Err(PeError::BadVersionInfo { offset: 0 })
+    );
+}
+
+#[test]
+fn version_info_write() {
+    let buf = read_test_pe64();
+    let mut info = PeHeader::new(&mut buf.clone())
+        .version_info()
+        .unwrap()
+        .unwrap();
+
+    // Shorter, stays where it is
+    info.set("CompanyName", "Zeo");
+    let mut out = info.write(&buf).unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+    let resource = pe_hdr
+        .find_resource(&ResourceId::Id(RT_VERSION), None)
+        .unwrap()
+        .unwrap();
+
+    assert_eq_hex!(resource.entry.data_rva, 0x4140);
+    assert_eq_hex!(resource.entry.size, 0x1D4);
+    assert_eq!(pe_hdr.version_info().unwrap().unwrap(), info);
+    assert_eq!(pe_hdr.sec_hdrs.len(), 4);
+
+    // Longer, moved to a new section
+    info.set("ProductName", "Zeo Release Pipeline");
+    info.set("LegalCopyright", "(c) Zeo");
+    info.fixed.as_mut().unwrap().set_file_version([2, 0, 0, 17]);
+    let mut out = info.write(&buf).unwrap();
+    let pe_hdr = PeHeader::new(&mut out);
+    let resource = pe_hdr
+        .find_resource(&ResourceId::Id(RT_VERSION), None)
+        .unwrap()
+        .unwrap();
+    let written = pe_hdr.version_info().unwrap().unwrap();
+
+    assert_eq!(pe_hdr.sec_hdrs.len(), 5);
+    assert_eq_hex!(resource.entry.data_rva, 0x5000);
+    assert_eq!(resource.data, info.build().unwrap());
+    assert_eq!(written.get("ProductName"), Some("Zeo Release Pipeline"));
+    assert_eq!(written.get("LegalCopyright"), Some("(c) Zeo"));
+    assert_eq!(written.fixed.unwrap().file_version(), [2, 0, 0, 17]);
+    assert_eq!(pe_hdr.resources().unwrap().len(), 4);
+
+    // Nothing to replace
+    assert_eq!(
+        VersionInfo::default().write(&read_test_pe()),
+        Err(PeError::ResourceNotFound)
+    );
+
+    // A string block's length field can't hold a value this long
+    info.set("Comments", &"x".repeat(0x8000));
+    assert!(matches!(
+        info.build(),
+        Err(PeError::VersionBlockTooLarge { len }) if len > 0x10000
+    ));
+    assert!(matches!(
+        info.write(&buf),
+        Err(PeError::VersionBlockTooLarge { .. })
+    ));
+}